@@ -0,0 +1,33 @@
+# Parallel random reads through the mount, first with a single worker
+# thread and then with one per CPU. Needs fio and an image from gen_img.sh.
+set -e
+mkdir -p foo
+cargo build --release
+for threads in 1 0; do
+    ./target/release/ext4libtest -o threads=$threads ./foo/ > /dev/null &
+    pid=$!
+    sleep 2
+    echo "== threads=$threads (0: one per CPU)"
+    fio --name=randread --directory=./foo --rw=randread --bs=4k --size=64M \
+        --numjobs=8 --direct=1 --ioengine=psync --time_based --runtime=20 \
+        --group_reporting
+    rm -f ./foo/randread.*
+    fusermount -u ./foo
+    wait $pid
+done
+
+# Sequential 1 GiB write and read back, first with small requests and no
+# kernel caching, then with the default I/O options.
+for opts in max_write=4096,max_readahead=4096,no_writeback_cache,sync_read,no_splice ""; do
+    ./target/release/ext4libtest ${opts:+-o $opts} ./foo/ > /dev/null &
+    pid=$!
+    sleep 2
+    echo "== ${opts:-default I/O options}"
+    dd if=/dev/zero of=./foo/large_file bs=1M count=1024 conv=fsync
+    sync ./foo/large_file
+    echo 3 | sudo tee /proc/sys/vm/drop_caches > /dev/null
+    dd if=./foo/large_file of=/dev/null bs=1M
+    rm -f ./foo/large_file
+    fusermount -u ./foo
+    wait $pid
+done
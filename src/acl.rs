@@ -0,0 +1,282 @@
+//! POSIX ACLs, converted between ext4's on-disk format and the user-space
+//! `system.posix_acl_*` xattr format used by getfacl/setfacl.
+
+use crate::ondisk::{le16, le32};
+use crate::EINVAL;
+
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+pub const ACL_READ: u16 = 0x04;
+pub const ACL_WRITE: u16 = 0x02;
+pub const ACL_EXECUTE: u16 = 0x01;
+
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const EXT4_ACL_VERSION: u32 = 1;
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+fn has_id(tag: u16) -> bool {
+    matches!(tag, ACL_USER | ACL_GROUP)
+}
+
+impl Acl {
+    /// Parses the user-space format: a version header followed by
+    /// `{ tag: u16, perm: u16, id: u32 }` records.
+    pub fn from_xattr(buf: &[u8]) -> Result<Self, i32> {
+        if buf.len() < 4 || (buf.len() - 4) % 8 != 0 {
+            return Err(EINVAL);
+        }
+        if le32(buf, 0) != POSIX_ACL_XATTR_VERSION {
+            return Err(EINVAL);
+        }
+
+        let entries = buf[4..]
+            .chunks(8)
+            .map(|chunk| {
+                let tag = le16(chunk, 0);
+                AclEntry {
+                    tag,
+                    perm: le16(chunk, 2),
+                    id: if has_id(tag) { le32(chunk, 4) } else { ACL_UNDEFINED_ID },
+                }
+            })
+            .collect();
+
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut buf = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            let id = if has_id(entry.tag) { entry.id } else { ACL_UNDEFINED_ID };
+            buf.extend_from_slice(&entry.tag.to_le_bytes());
+            buf.extend_from_slice(&entry.perm.to_le_bytes());
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Parses ext4's on-disk format, where only named entries carry an id.
+    pub fn from_disk(buf: &[u8]) -> Result<Self, i32> {
+        if buf.len() < 4 || le32(buf, 0) != EXT4_ACL_VERSION {
+            return Err(EINVAL);
+        }
+
+        let mut entries = Vec::new();
+        let mut off = 4;
+        while off < buf.len() {
+            if off + 4 > buf.len() {
+                return Err(EINVAL);
+            }
+            let tag = le16(buf, off);
+            let perm = le16(buf, off + 2);
+            let id = if has_id(tag) {
+                if off + 8 > buf.len() {
+                    return Err(EINVAL);
+                }
+                let id = le32(buf, off + 4);
+                off += 8;
+                id
+            } else {
+                off += 4;
+                ACL_UNDEFINED_ID
+            };
+            entries.push(AclEntry { tag, perm, id });
+        }
+
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    pub fn to_disk(&self) -> Vec<u8> {
+        let mut buf = EXT4_ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            buf.extend_from_slice(&entry.tag.to_le_bytes());
+            buf.extend_from_slice(&entry.perm.to_le_bytes());
+            if has_id(entry.tag) {
+                buf.extend_from_slice(&entry.id.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Entry order and multiplicity rules of `posix_acl_valid`.
+    fn validate(&self) -> Result<(), i32> {
+        #[derive(PartialEq)]
+        enum State {
+            UserObj,
+            User,
+            Group,
+            Other,
+            Done,
+        }
+
+        let mut state = State::UserObj;
+        let mut needs_mask = false;
+        for entry in &self.entries {
+            if entry.perm & !(ACL_READ | ACL_WRITE | ACL_EXECUTE) != 0 {
+                return Err(EINVAL);
+            }
+            state = match (entry.tag, state) {
+                (ACL_USER_OBJ, State::UserObj) => State::User,
+                (ACL_USER, State::User) => {
+                    needs_mask = true;
+                    State::User
+                }
+                (ACL_GROUP_OBJ, State::User) => State::Group,
+                (ACL_GROUP, State::Group) => {
+                    needs_mask = true;
+                    State::Group
+                }
+                (ACL_MASK, State::Group) => State::Other,
+                (ACL_OTHER, State::Other) => State::Done,
+                (ACL_OTHER, State::Group) if !needs_mask => State::Done,
+                _ => return Err(EINVAL),
+            };
+        }
+
+        if state == State::Done {
+            Ok(())
+        } else {
+            Err(EINVAL)
+        }
+    }
+
+    /// Returns the permission bits the ACL is equivalent to, and whether it
+    /// can be represented by those bits alone.
+    pub fn equiv_mode(&self) -> (u32, bool) {
+        let mut mode = 0;
+        let mut equiv = true;
+        for entry in &self.entries {
+            let perm = entry.perm as u32;
+            match entry.tag {
+                ACL_USER_OBJ => mode |= perm << 6,
+                ACL_GROUP_OBJ => mode |= perm << 3,
+                ACL_OTHER => mode |= perm,
+                ACL_MASK => {
+                    mode = (mode & !0o070) | (perm << 3);
+                    equiv = false;
+                }
+                _ => equiv = false,
+            }
+        }
+        (mode, equiv)
+    }
+
+    /// `posix_acl_permission`: whether the caller gets all `want` bits.
+    pub fn permits(
+        &self,
+        uid: u32,
+        in_group: impl Fn(u32) -> bool,
+        owner: u32,
+        owner_group: u32,
+        want: u16,
+    ) -> bool {
+        let mut found = false;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let matched = match entry.tag {
+                ACL_USER_OBJ if owner == uid => return (entry.perm & want) == want,
+                ACL_USER => entry.id == uid,
+                ACL_GROUP_OBJ | ACL_GROUP => {
+                    let gid = if entry.tag == ACL_GROUP_OBJ {
+                        owner_group
+                    } else {
+                        entry.id
+                    };
+                    if in_group(gid) {
+                        found = true;
+                        (entry.perm & want) == want
+                    } else {
+                        false
+                    }
+                }
+                ACL_OTHER => return !found && (entry.perm & want) == want,
+                _ => false,
+            };
+
+            if matched {
+                let mask = self.entries[i + 1..]
+                    .iter()
+                    .find(|entry| entry.tag == ACL_MASK)
+                    .map_or(u16::MAX, |mask| mask.perm);
+                return (entry.perm & mask & want) == want;
+            }
+        }
+        false
+    }
+
+    /// `posix_acl_create_masq`: restricts an inherited ACL to the requested
+    /// `mode` and narrows `mode` to match. Returns whether the ACL still
+    /// needs to be stored.
+    pub fn create_masq(&mut self, mode: &mut u32) -> bool {
+        let mut bits = *mode & 0o777;
+        let mut not_equiv = false;
+        let mut group_obj = None;
+        let mut mask_obj = None;
+
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            match entry.tag {
+                ACL_USER_OBJ => {
+                    entry.perm &= ((bits >> 6) & 7) as u16;
+                    bits &= ((entry.perm as u32) << 6) | !0o700;
+                }
+                ACL_OTHER => {
+                    entry.perm &= (bits & 7) as u16;
+                    bits &= entry.perm as u32 | !0o007;
+                }
+                ACL_GROUP_OBJ => group_obj = Some(i),
+                ACL_MASK => {
+                    mask_obj = Some(i);
+                    not_equiv = true;
+                }
+                _ => not_equiv = true,
+            }
+        }
+
+        if let Some(i) = mask_obj.or(group_obj) {
+            let entry = &mut self.entries[i];
+            entry.perm &= ((bits >> 3) & 7) as u16;
+            bits &= ((entry.perm as u32) << 3) | !0o070;
+        }
+
+        *mode = (*mode & !0o777) | bits;
+        not_equiv
+    }
+
+    /// `posix_acl_chmod_masq`: carries a chmod over into the ACL.
+    pub fn chmod(&mut self, mode: u32) {
+        let mut group_obj = None;
+        let mut mask_obj = None;
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm = ((mode >> 6) & 7) as u16,
+                ACL_OTHER => entry.perm = (mode & 7) as u16,
+                ACL_GROUP_OBJ => group_obj = Some(i),
+                ACL_MASK => mask_obj = Some(i),
+                _ => {}
+            }
+        }
+        if let Some(i) = mask_obj.or(group_obj) {
+            self.entries[i].perm = ((mode >> 3) & 7) as u16;
+        }
+    }
+}
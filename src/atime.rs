@@ -0,0 +1,124 @@
+//! When reads update atime, as the noatime, relatime and strictatime mount
+//! options decide, and the timestamp updates lazytime holds back from the
+//! disk.
+
+use crate::ondisk::InodeTime;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// relatime still updates an atime older than this.
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimePolicy {
+    /// Never update atime on reads.
+    NoAtime,
+    /// Update atime only if it is not newer than mtime or ctime, or is a
+    /// day old, so that tools comparing them still work.
+    #[default]
+    Relatime,
+    /// Update atime on every read.
+    StrictAtime,
+}
+
+impl AtimePolicy {
+    pub fn wants_update(
+        self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        match self {
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::StrictAtime => true,
+            AtimePolicy::Relatime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now
+                        .duration_since(atime)
+                        .is_ok_and(|age| age >= RELATIME_MAX_AGE)
+            }
+        }
+    }
+}
+
+/// Timestamps kept in memory under lazytime, until fsync, unmount, the
+/// kernel forgetting the inode, or `LAZYTIME_MAX_AGE`: atime after reads,
+/// and mtime and ctime after writes. Anything else that stores the inode
+/// writes its own timestamps and drops the pending ones it replaces.
+#[derive(Debug, Default)]
+pub struct LazyTimes {
+    /// The pending timestamps of each inode, with when the first of them
+    /// still pending was held.
+    pending: Mutex<HashMap<u64, (Vec<(InodeTime, SystemTime)>, Instant)>>,
+}
+
+/// How long a timestamp is held back at most, as the kernel's
+/// `dirtytime_expire_interval` bounds it.
+pub const LAZYTIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl LazyTimes {
+    /// Holds back setting the `which` timestamps of `ino` to `time`.
+    pub fn set(&self, ino: u64, which: &[InodeTime], time: SystemTime) {
+        let mut pending = self.pending.lock().unwrap();
+        let (times, _) = pending
+            .entry(ino)
+            .or_insert_with(|| (Vec::new(), Instant::now()));
+        times.retain(|(held, _)| !which.contains(held));
+        times.extend(which.iter().map(|&which| (which, time)));
+    }
+
+    pub fn get(&self, ino: u64, which: InodeTime) -> Option<SystemTime> {
+        let pending = self.pending.lock().unwrap();
+        let (times, _) = pending.get(&ino)?;
+        times
+            .iter()
+            .find(|(held, _)| *held == which)
+            .map(|&(_, time)| time)
+    }
+
+    /// Drops the `which` timestamps held back for `ino`, which are being
+    /// replaced on disk.
+    pub fn forget(&self, ino: u64, which: &[InodeTime]) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some((times, _)) = pending.get_mut(&ino) else {
+            return;
+        };
+        times.retain(|(held, _)| !which.contains(held));
+        if times.is_empty() {
+            pending.remove(&ino);
+        }
+    }
+
+    pub fn take(&self, ino: u64) -> Vec<(InodeTime, SystemTime)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&ino)
+            .map_or_else(Vec::new, |(times, _)| times)
+    }
+
+    pub fn take_all(&self) -> Vec<(u64, Vec<(InodeTime, SystemTime)>)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(ino, (times, _))| (ino, times))
+            .collect()
+    }
+
+    /// Takes the timestamps held back for `max_age` or longer.
+    pub fn take_older(&self, max_age: Duration) -> Vec<(u64, Vec<(InodeTime, SystemTime)>)> {
+        let mut pending = self.pending.lock().unwrap();
+        let old: Vec<u64> = pending
+            .iter()
+            .filter(|(_, (_, held))| held.elapsed() >= max_age)
+            .map(|(&ino, _)| ino)
+            .collect();
+        old.into_iter()
+            .filter_map(|ino| pending.remove(&ino).map(|(times, _)| (ino, times)))
+            .collect()
+    }
+}
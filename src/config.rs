@@ -0,0 +1,140 @@
+//! Command line options: `ext4libtest [-o opt[,opt...]] <mountpoint>`.
+
+use crate::atime::AtimePolicy;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mountpoint: String,
+    /// Leave permission checks to the kernel, based on the mode bits only.
+    pub default_permissions: bool,
+    /// Mount read-only.
+    pub read_only: bool,
+    /// Worker threads serving requests, or 0 for one per CPU.
+    pub threads: usize,
+    /// Largest write request the kernel may send, in bytes.
+    pub max_write: u32,
+    /// Largest readahead the kernel may do, in bytes.
+    pub max_readahead: u32,
+    /// Let the kernel cache writes and flush them in large requests.
+    pub writeback_cache: bool,
+    /// Let the kernel have several reads of one file in flight.
+    pub async_read: bool,
+    /// Let the kernel move request data with splice(2).
+    pub splice: bool,
+    /// How long the kernel may cache a name lookup.
+    pub entry_ttl: Duration,
+    /// How long the kernel may cache attributes.
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache a failed lookup, or zero not to.
+    pub negative_ttl: Duration,
+    /// When reads update atime.
+    pub atime: AtimePolicy,
+    /// Hold atime updates, and mtime and ctime updates from writes, in
+    /// memory until fsync or unmount.
+    pub lazytime: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mountpoint: String::new(),
+            default_permissions: false,
+            read_only: false,
+            threads: 0,
+            max_write: 1 << 20,
+            max_readahead: 1 << 20,
+            writeback_cache: true,
+            async_read: true,
+            splice: true,
+            entry_ttl: Duration::from_secs(1),
+            attr_ttl: Duration::from_secs(1),
+            negative_ttl: Duration::ZERO,
+            atime: AtimePolicy::default(),
+            lazytime: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut mountpoint = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "-o" {
+                let opts = args.next().ok_or("-o requires an argument")?;
+                for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+                    config.apply_option(opt)?;
+                }
+            } else if mountpoint.is_none() {
+                mountpoint = Some(arg.clone());
+            } else {
+                return Err(format!("unexpected argument {:?}", arg));
+            }
+        }
+
+        config.mountpoint = mountpoint.ok_or("No mount point specified!")?;
+        Ok(config)
+    }
+
+    fn apply_option(&mut self, opt: &str) -> Result<(), String> {
+        match opt {
+            "default_permissions" => self.default_permissions = true,
+            "ro" => self.read_only = true,
+            "rw" => self.read_only = false,
+            "writeback_cache" => self.writeback_cache = true,
+            "no_writeback_cache" => self.writeback_cache = false,
+            "async_read" => self.async_read = true,
+            "sync_read" => self.async_read = false,
+            "splice" => self.splice = true,
+            "no_splice" => self.splice = false,
+            "noatime" => self.atime = AtimePolicy::NoAtime,
+            "relatime" => self.atime = AtimePolicy::Relatime,
+            "strictatime" => self.atime = AtimePolicy::StrictAtime,
+            "lazytime" => self.lazytime = true,
+            "nolazytime" => self.lazytime = false,
+            _ => match opt.split_once('=') {
+                Some(("threads", n)) => {
+                    self.threads = n
+                        .parse()
+                        .map_err(|_| format!("invalid thread count {:?}", n))?;
+                }
+                Some(("max_write", n)) => self.max_write = parse_size(n)?,
+                Some(("max_readahead", n)) => self.max_readahead = parse_size(n)?,
+                Some(("entry_timeout", secs)) => self.entry_ttl = parse_timeout(secs)?,
+                Some(("attr_timeout", secs)) => self.attr_ttl = parse_timeout(secs)?,
+                Some(("negative_timeout", secs)) => self.negative_ttl = parse_timeout(secs)?,
+                _ => return Err(format!("unknown mount option {:?}", opt)),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// A byte count, optionally with a `k` or `m` suffix.
+fn parse_size(value: &str) -> Result<u32, String> {
+    let (digits, shift) = match value.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 10),
+        None => match value.strip_suffix(['m', 'M']) {
+            Some(digits) => (digits, 20),
+            None => (value, 0),
+        },
+    };
+    digits
+        .parse::<u32>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("invalid size {:?}", value))
+}
+
+/// Seconds, possibly fractional, as libfuse takes its timeouts.
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid timeout {:?}", value))
+}
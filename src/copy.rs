@@ -0,0 +1,256 @@
+//! copy_file_range(2) inside the image: whole blocks are copied on disk
+//! extent by extent, and source holes stay holes in the destination.
+
+use crate::extent::{Extent, ExtentStore};
+use crate::{errno, EFBIG, EINVAL, ENXIO, EOPNOTSUPP, SEEK_DATA, SEEK_HOLE};
+use ext4_rs::{BlockDevice, BLOCK_SIZE};
+
+const BS: u64 = BLOCK_SIZE as u64;
+/// Bytes moved per read/write round trip for unaligned parts.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Copies up to `len` bytes and returns how many were copied, which is
+/// short when the source ends first or, as with write(2), when an error
+/// such as ENOSPC stops the copy after some bytes made it.
+pub fn copy_file_range(
+    store: &ExtentStore,
+    ino_in: u32,
+    offset_in: u64,
+    ino_out: u32,
+    offset_out: u64,
+    len: u64,
+) -> Result<u64, i32> {
+    let size_in = store.size(ino_in);
+    if offset_in >= size_in || len == 0 {
+        return Ok(0);
+    }
+    let len = len.min(size_in - offset_in);
+    if ino_in == ino_out && offset_in < offset_out + len && offset_out < offset_in + len {
+        return Err(EINVAL);
+    }
+
+    let mut copied = 0;
+    match copy_range(
+        store,
+        ino_in,
+        offset_in,
+        ino_out,
+        offset_out,
+        len,
+        &mut copied,
+    ) {
+        Ok(()) => {}
+        Err(e) if copied > 0 => {
+            log::warn!(
+                "copy to inode {} stopped after {} bytes: {}",
+                ino_out,
+                copied,
+                e
+            );
+        }
+        Err(e) => return Err(e),
+    }
+
+    let end = offset_out + copied;
+    if end > store.size(ino_out) {
+        store.set_size(ino_out, end);
+    }
+    Ok(copied)
+}
+
+/// Copies `len` bytes, adding to `copied` as each part is done.
+fn copy_range(
+    store: &ExtentStore,
+    ino_in: u32,
+    offset_in: u64,
+    ino_out: u32,
+    offset_out: u64,
+    len: u64,
+    copied: &mut u64,
+) -> Result<(), i32> {
+    if offset_in % BS != offset_out % BS {
+        return copy_bytes(store, ino_in, offset_in, ino_out, offset_out, len, copied);
+    }
+
+    // Whole source blocks in the range; the edges go through read/write.
+    let first = offset_in.div_ceil(BS);
+    let last = (offset_in + len) / BS;
+    if first >= last {
+        return copy_bytes(store, ino_in, offset_in, ino_out, offset_out, len, copied);
+    }
+
+    let head = first * BS - offset_in;
+    let tail = offset_in + len - last * BS;
+    copy_bytes(store, ino_in, offset_in, ino_out, offset_out, head, copied)?;
+    match copy_blocks(
+        store,
+        ino_in,
+        first as u32,
+        last as u32,
+        ino_out,
+        (offset_out + head) / BS,
+        copied,
+    ) {
+        Ok(()) => {}
+        Err(EOPNOTSUPP) => {
+            copy_bytes(
+                store,
+                ino_in,
+                first * BS,
+                ino_out,
+                offset_out + head,
+                (last - first) * BS,
+                copied,
+            )?;
+        }
+        Err(e) => return Err(e),
+    }
+    copy_bytes(
+        store,
+        ino_in,
+        last * BS,
+        ino_out,
+        offset_out + len - tail,
+        tail,
+        copied,
+    )
+}
+
+/// Replaces blocks from `dest` on in `ino_out` with copies of blocks
+/// `first..last` of `ino_in`, leaving holes where the source has none.
+/// When blocks run out it stops at the source extent it could not copy,
+/// leaving the destination from there on as it was.
+fn copy_blocks(
+    store: &ExtentStore,
+    ino_in: u32,
+    first: u32,
+    last: u32,
+    ino_out: u32,
+    dest: u64,
+    copied: &mut u64,
+) -> Result<(), i32> {
+    let src = store.load(ino_in)?;
+    let mut dst = store.load(ino_out)?;
+    let dest = u32::try_from(dest).map_err(|_| EFBIG)?;
+    dest.checked_add(last - first).ok_or(EFBIG)?;
+
+    // Allocate first, so that a shortage is known before anything changes.
+    let mut result = Ok(());
+    let mut done = last;
+    let mut pieces = Vec::new();
+    let mut allocated = Vec::new();
+    let sources: Vec<Extent> = src
+        .overlapping(first, last)
+        .filter(|ext| !ext.unwritten)
+        .copied()
+        .collect();
+    for ext in sources {
+        let start = ext.lblock.max(first);
+        let end = ext.end().min(last);
+        let lblock = dest + (start - first);
+        match store.allocate(ino_out, lblock, end - start, dst.goal(lblock)) {
+            Ok(copies) => {
+                allocated.extend_from_slice(&copies);
+                pieces.push((ext.pblock + (start - ext.lblock) as u64, copies));
+            }
+            Err(e) => {
+                result = Err(e);
+                done = start;
+                break;
+            }
+        }
+    }
+
+    let freed = dst.remove(dest, dest + (done - first));
+    for (mut src_block, copies) in pieces {
+        for mut copy in copies {
+            for i in 0..copy.len as u64 {
+                let buf = store
+                    .disk
+                    .read_offset((src_block + i) as usize * BLOCK_SIZE);
+                store
+                    .disk
+                    .write_offset((copy.pblock + i) as usize * BLOCK_SIZE, &buf);
+            }
+            src_block += copy.len as u64;
+            copy.unwritten = false;
+            dst.insert(copy);
+        }
+    }
+
+    if let Err(e) = store.store(ino_out, &mut dst, &freed) {
+        store.free(ino_out, &allocated);
+        return Err(e);
+    }
+    *copied += (done - first) as u64 * BS;
+    result
+}
+
+/// Copies through ext4_rs reads and writes, skipping source holes that
+/// land past the end of the destination. `copied` grows with each hole
+/// skipped and each chunk written.
+fn copy_bytes(
+    store: &ExtentStore,
+    ino_in: u32,
+    offset_in: u64,
+    ino_out: u32,
+    offset_out: u64,
+    len: u64,
+    copied: &mut u64,
+) -> Result<(), i32> {
+    let end = offset_in + len;
+    let mut pos = offset_in;
+    while pos < end {
+        let data_start = match store.seek(ino_in, pos, SEEK_DATA) {
+            Ok(data) => data.min(end),
+            Err(ENXIO) => end,
+            Err(e) => return Err(e),
+        };
+        if data_start > pos {
+            let out = offset_out + (pos - offset_in);
+            let size_out = store.size(ino_out);
+            if out < size_out {
+                // The destination has contents there that must read as zeros.
+                let zeros = vec![0u8; (data_start.min(pos + (size_out - out)) - pos) as usize];
+                write_chunked(store, ino_out, out, &zeros)?;
+            }
+            *copied += data_start - pos;
+            pos = data_start;
+            continue;
+        }
+
+        let data_end = match store.seek(ino_in, pos, SEEK_HOLE) {
+            Ok(hole) => hole.min(end),
+            Err(e) => return Err(e),
+        };
+        while pos < data_end {
+            let n = (data_end - pos).min(CHUNK_SIZE);
+            let data = store
+                .ext4
+                .ext4_file_read(ino_in as u64, n as u32, pos as i64)
+                .map_err(|e| {
+                    log::error!("copy read of inode {} failed: {:?}", ino_in, e);
+                    errno::from_ext4(e)
+                })?;
+            write_chunked(store, ino_out, offset_out + (pos - offset_in), &data)?;
+            *copied += n;
+            pos += n;
+        }
+    }
+    Ok(())
+}
+
+fn write_chunked(store: &ExtentStore, ino: u32, offset: u64, data: &[u8]) -> Result<(), i32> {
+    for (i, chunk) in data.chunks(CHUNK_SIZE as usize).enumerate() {
+        let offset = offset + i as u64 * CHUNK_SIZE;
+        store.prepare_write(ino, offset, chunk.len() as u64)?;
+        store
+            .ext4
+            .ext4_file_write(ino as u64, offset as i64, chunk)
+            .map_err(|e| {
+                log::error!("copy write to inode {} failed: {:?}", ino, e);
+                errno::from_ext4(e)
+            })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+//! Credentials of the process behind a FUSE request.
+
+use fuser::Request;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long the groups read for a pid are reused. A process rarely changes
+/// its groups, and a stream of requests from it should not read /proc each.
+const GROUPS_TTL: Duration = Duration::from_secs(1);
+
+/// The caller identity of a request, kept past the `Request` it came
+/// with for the worker thread that serves it.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    groups: Arc<Vec<u32>>,
+}
+
+impl Caller {
+    pub fn new(uid: u32, gid: u32, pid: u32, groups: Arc<Vec<u32>>) -> Self {
+        Self {
+            uid,
+            gid,
+            pid,
+            groups,
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+/// The groups are read here, on the session thread, while the caller is
+/// still blocked in the request; a worker may only get to it after the
+/// caller has exited.
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Self::new(
+            req.uid(),
+            req.gid(),
+            req.pid(),
+            supplementary_groups(req.pid()),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn from_request(req: &Caller) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+            groups: req.groups.to_vec(),
+        }
+    }
+
+    /// Root bypasses read/write checks, like CAP_DAC_OVERRIDE and CAP_FOWNER.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// FUSE only passes the primary gid, so the supplementary groups are read
+/// from /proc, at most once per `GROUPS_TTL` for a pid.
+fn supplementary_groups(pid: u32) -> Arc<Vec<u32>> {
+    static CACHE: OnceLock<Mutex<HashMap<u32, (Instant, Arc<Vec<u32>>)>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    let now = Instant::now();
+    if let Some((read_at, groups)) = cache.lock().unwrap().get(&pid) {
+        if now.duration_since(*read_at) < GROUPS_TTL {
+            return groups.clone();
+        }
+    }
+
+    let groups = Arc::new(read_groups(pid));
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|_, (read_at, _)| now.duration_since(*read_at) < GROUPS_TTL);
+    cache.insert(pid, (now, groups.clone()));
+    groups
+}
+
+fn read_groups(pid: u32) -> Vec<u32> {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return Vec::new();
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
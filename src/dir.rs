@@ -0,0 +1,47 @@
+//! Open directory handles. readdir at offset 0 takes a snapshot of the
+//! entries and later offsets index into it, so telldir/seekdir positions
+//! stay valid while entries are created and removed concurrently.
+
+use fuser::FileType;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+#[derive(Default)]
+pub struct DirHandles {
+    next_fh: u64,
+    snapshots: HashMap<u64, Arc<[DirEntry]>>,
+}
+
+impl DirHandles {
+    /// Returns a new handle, never 0.
+    pub fn open(&mut self) -> u64 {
+        self.next_fh += 1;
+        self.snapshots.insert(self.next_fh, Arc::from([]));
+        self.next_fh
+    }
+
+    /// The snapshot of `fh`, which stays valid while the handle is read
+    /// from other threads.
+    pub fn get(&self, fh: u64) -> Option<Arc<[DirEntry]>> {
+        self.snapshots.get(&fh).cloned()
+    }
+
+    /// Replaces the snapshot of `fh`, on the first read and on rewinddir.
+    pub fn fill(&mut self, fh: u64, entries: Vec<DirEntry>) -> Arc<[DirEntry]> {
+        let entries: Arc<[DirEntry]> = entries.into();
+        self.snapshots.insert(fh, entries.clone());
+        entries
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.snapshots.remove(&fh);
+    }
+}
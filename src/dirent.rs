@@ -0,0 +1,332 @@
+//! Raw directory entries, for the parts of them ext4_rs does not fill in
+//! and for names it cannot take, which are not UTF-8.
+
+use crate::extent::{Extent, ExtentStore, ExtentTree};
+use crate::ondisk::{self, le16, le32, put16, put32};
+use crate::{
+    EFBIG, EINVAL, ENOENT, EOPNOTSUPP, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT,
+    S_IFSOCK,
+};
+use ext4_rs::{BlockDevice, BLOCK_SIZE};
+
+pub const EXT4_FT_REG_FILE: u8 = 1;
+pub const EXT4_FT_DIR: u8 = 2;
+pub const EXT4_FT_CHRDEV: u8 = 3;
+pub const EXT4_FT_BLKDEV: u8 = 4;
+pub const EXT4_FT_FIFO: u8 = 5;
+pub const EXT4_FT_SOCK: u8 = 6;
+pub const EXT4_FT_SYMLINK: u8 = 7;
+
+/// Longest name a directory entry can hold.
+pub const EXT4_NAME_LEN: usize = 255;
+
+const DIRENT_HEADER_SIZE: usize = 8;
+/// `struct ext4_dir_entry_tail` at the end of each leaf block.
+const TAIL_SIZE: usize = 12;
+const TAIL_FT: u8 = 0xDE;
+/// The directory has an htree index.
+const EXT4_INDEX_FL: u32 = 0x1000;
+
+/// The `file_type` of a directory entry for an inode of `mode`.
+pub fn file_type(mode: u32) -> u8 {
+    match mode & S_IFMT {
+        S_IFDIR => EXT4_FT_DIR,
+        S_IFCHR => EXT4_FT_CHRDEV,
+        S_IFBLK => EXT4_FT_BLKDEV,
+        S_IFIFO => EXT4_FT_FIFO,
+        S_IFSOCK => EXT4_FT_SOCK,
+        S_IFLNK => EXT4_FT_SYMLINK,
+        _ => EXT4_FT_REG_FILE,
+    }
+}
+
+/// A directory entry with its name as stored, in whatever encoding the
+/// system that wrote it used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub ino: u32,
+    pub file_type: u8,
+    pub name: Vec<u8>,
+}
+
+/// Every live entry of `dir`, "." and ".." included, in on-disk order.
+pub fn read_dir(store: &ExtentStore, dir: u32) -> Result<Vec<RawEntry>, i32> {
+    let tree = store.load(dir)?;
+    let mut entries = Vec::new();
+    for offset in leaf_blocks(&tree) {
+        let block = store.disk.read_offset(offset);
+        for pos in entry_offsets(&block) {
+            let ino = le32(&block, pos);
+            // Unused entries and the checksum tail.
+            if ino == 0 {
+                continue;
+            }
+            entries.push(RawEntry {
+                ino,
+                file_type: block[pos + 7],
+                name: entry_name(&block, pos).to_vec(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// The inode `name` refers to in `dir`.
+pub fn lookup(store: &ExtentStore, dir: u32, name: &[u8]) -> Result<u32, i32> {
+    read_dir(store, dir)?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.ino)
+        .ok_or(ENOENT)
+}
+
+/// Rewrites the `file_type` of the entry `name` for `ino` in `dir`.
+pub fn set_file_type(
+    store: &ExtentStore,
+    dir: u32,
+    name: &[u8],
+    ino: u32,
+    ft: u8,
+) -> Result<(), i32> {
+    update_entry(store, dir, name, ino, |entry| entry[7] = ft)
+}
+
+/// Points the entry `name` for `ino` in `dir` at `new_ino`, of type `ft`.
+pub fn set_inode(
+    store: &ExtentStore,
+    dir: u32,
+    name: &[u8],
+    ino: u32,
+    new_ino: u32,
+    ft: u8,
+) -> Result<(), i32> {
+    update_entry(store, dir, name, ino, |entry| {
+        put32(entry, 0, new_ino);
+        entry[7] = ft;
+    })
+}
+
+/// Adds the entry `name` for `ino` to `dir`, in the first leaf block with
+/// room for it or else in a new block at the end. Indexed directories are
+/// refused: the entry would have to go in the block its name hashes to.
+pub fn add_entry(store: &ExtentStore, dir: u32, name: &[u8], ino: u32, ft: u8) -> Result<(), i32> {
+    if name.is_empty() || name.len() > EXT4_NAME_LEN {
+        return Err(EINVAL);
+    }
+    let raw = store.layout.read_inode(store.disk, dir);
+    if le32(&raw, ondisk::I_FLAGS) & EXT4_INDEX_FL != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    let needed = rec_len(name.len());
+
+    let tree = store.load(dir)?;
+    for offset in leaf_blocks(&tree) {
+        let mut block = store.disk.read_offset(offset);
+        let Some((pos, used)) = free_slot(&block, needed) else {
+            continue;
+        };
+        let free = le16(&block, pos + 4) as usize - used;
+        if used > 0 {
+            put16(&mut block, pos + 4, used as u16);
+        }
+        write_entry(&mut block[pos + used..], ino, free, ft, name);
+        write_block(store, dir, offset, &mut block);
+        return Ok(());
+    }
+
+    let lblock = ondisk::inode_size(&raw).div_ceil(BLOCK_SIZE as u64);
+    let lblock = u32::try_from(lblock).map_err(|_| EFBIG)?;
+    let offset = append_block(store, dir, &tree, lblock)?;
+    let (mut block, end) = empty_leaf(store);
+    write_entry(&mut block, ino, end, ft, name);
+    write_block(store, dir, offset, &mut block);
+    store.set_size(dir, (lblock as u64 + 1) * BLOCK_SIZE as u64);
+    Ok(())
+}
+
+/// Removes the entry `name` for `ino` from `dir`, giving its space to the
+/// entry before it as ext4 does.
+pub fn remove_entry(store: &ExtentStore, dir: u32, name: &[u8], ino: u32) -> Result<(), i32> {
+    let tree = store.load(dir)?;
+    for offset in leaf_blocks(&tree) {
+        let mut block = store.disk.read_offset(offset);
+        let positions: Vec<usize> = entry_offsets(&block).collect();
+        let Some(i) = positions
+            .iter()
+            .position(|&pos| le32(&block, pos) == ino && entry_name(&block, pos) == name)
+        else {
+            continue;
+        };
+        let pos = positions[i];
+        match i.checked_sub(1).map(|prev| positions[prev]) {
+            Some(prev) => {
+                let merged = le16(&block, prev + 4) + le16(&block, pos + 4);
+                put16(&mut block, prev + 4, merged);
+            }
+            // The first entry of a block is only marked unused.
+            None => put32(&mut block, pos, 0),
+        }
+        write_block(store, dir, offset, &mut block);
+        return Ok(());
+    }
+    Err(ENOENT)
+}
+
+/// Gives the new directory `dir` its first block, with "." and "..".
+pub fn init_dir(store: &ExtentStore, dir: u32, parent: u32) -> Result<(), i32> {
+    let tree = store.load(dir)?;
+    let offset = append_block(store, dir, &tree, 0)?;
+    let (mut block, end) = empty_leaf(store);
+    let dot = rec_len(1);
+    write_entry(&mut block, dir, dot, EXT4_FT_DIR, b".");
+    write_entry(&mut block[dot..], parent, end - dot, EXT4_FT_DIR, b"..");
+    write_block(store, dir, offset, &mut block);
+    store.set_size(dir, BLOCK_SIZE as u64);
+    Ok(())
+}
+
+/// Applies `update` to the entry `name` for `ino` in `dir` and refreshes
+/// the checksum of its block.
+fn update_entry(
+    store: &ExtentStore,
+    dir: u32,
+    name: &[u8],
+    ino: u32,
+    update: impl FnOnce(&mut [u8]),
+) -> Result<(), i32> {
+    let tree = store.load(dir)?;
+    for offset in leaf_blocks(&tree) {
+        let mut block = store.disk.read_offset(offset);
+        let Some(pos) = find_entry(&block, name, ino) else {
+            continue;
+        };
+        update(&mut block[pos..]);
+        write_block(store, dir, offset, &mut block);
+        return Ok(());
+    }
+    Err(ENOENT)
+}
+
+/// Disk offsets of the blocks of a directory that can hold entries.
+fn leaf_blocks(tree: &ExtentTree) -> impl Iterator<Item = usize> + '_ {
+    tree.extents
+        .iter()
+        .filter(|ext| !ext.unwritten)
+        .flat_map(|ext| (0..ext.len as u64).map(move |i| (ext.pblock + i) as usize * BLOCK_SIZE))
+}
+
+/// Maps a new block at `lblock` of `dir` and returns its disk offset.
+fn append_block(
+    store: &ExtentStore,
+    dir: u32,
+    tree: &ExtentTree,
+    lblock: u32,
+) -> Result<usize, i32> {
+    let mut tree = tree.clone();
+    let extents = store.allocate(dir, lblock, 1, tree.goal(lblock))?;
+    for ext in &extents {
+        tree.insert(Extent {
+            unwritten: false,
+            ..*ext
+        });
+    }
+    if let Err(e) = store.store(dir, &mut tree, &[]) {
+        store.free(dir, &extents);
+        return Err(e);
+    }
+    Ok(extents[0].pblock as usize * BLOCK_SIZE)
+}
+
+/// A new leaf block, with the checksum tail if the filesystem has them,
+/// and where the space for entries ends.
+fn empty_leaf(store: &ExtentStore) -> (Vec<u8>, usize) {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    if !store.layout.has_metadata_csum() {
+        return (block, BLOCK_SIZE);
+    }
+    let tail = BLOCK_SIZE - TAIL_SIZE;
+    put16(&mut block, tail + 4, TAIL_SIZE as u16);
+    block[tail + 7] = TAIL_FT;
+    (block, tail)
+}
+
+/// Writes back a leaf block of `dir`, refreshing its checksum.
+fn write_block(store: &ExtentStore, dir: u32, offset: usize, block: &mut [u8]) {
+    if store.layout.has_metadata_csum() && has_tail(block) {
+        let generation = le32(
+            &store.layout.read_inode(store.disk, dir),
+            ondisk::I_GENERATION,
+        );
+        let seed = store.layout.inode_csum_seed(dir, generation);
+        let csum = ondisk::crc32c(seed, &block[..BLOCK_SIZE - TAIL_SIZE]);
+        put32(block, BLOCK_SIZE - 4, csum);
+    }
+    store.disk.write_offset(offset, block);
+}
+
+fn write_entry(entry: &mut [u8], ino: u32, rec_len: usize, ft: u8, name: &[u8]) {
+    put32(entry, 0, ino);
+    put16(entry, 4, rec_len as u16);
+    entry[6] = name.len() as u8;
+    entry[7] = ft;
+    entry[DIRENT_HEADER_SIZE..DIRENT_HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+/// Space taken by an entry with a name of `name_len` bytes.
+fn rec_len(name_len: usize) -> usize {
+    (DIRENT_HEADER_SIZE + name_len + 3) & !3
+}
+
+/// An entry in `block` with room for `needed` more bytes, and how many of
+/// its own bytes it keeps: none if it is unused.
+fn free_slot(block: &[u8], needed: usize) -> Option<(usize, usize)> {
+    let end = if has_tail(block) {
+        BLOCK_SIZE - TAIL_SIZE
+    } else {
+        BLOCK_SIZE
+    };
+    entry_offsets(block)
+        .take_while(|&pos| pos < end)
+        .find_map(|pos| {
+            let used = match le32(block, pos) {
+                0 => 0,
+                _ => rec_len(block[pos + 6] as usize),
+            };
+            (le16(block, pos + 4) as usize >= used + needed).then_some((pos, used))
+        })
+}
+
+fn find_entry(block: &[u8], name: &[u8], ino: u32) -> Option<usize> {
+    entry_offsets(block).find(|&pos| le32(block, pos) == ino && entry_name(block, pos) == name)
+}
+
+/// Offsets of the entries in a leaf block, up to the first corrupt one.
+fn entry_offsets(block: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos + DIRENT_HEADER_SIZE > block.len() {
+            return None;
+        }
+        let rec_len = le16(block, pos + 4) as usize;
+        let name_len = block[pos + 6] as usize;
+        if rec_len < DIRENT_HEADER_SIZE
+            || pos + rec_len > block.len()
+            || DIRENT_HEADER_SIZE + name_len > rec_len
+        {
+            return None;
+        }
+        let entry = pos;
+        pos += rec_len;
+        Some(entry)
+    })
+}
+
+fn entry_name(block: &[u8], pos: usize) -> &[u8] {
+    let name_len = block[pos + 6] as usize;
+    &block[pos + DIRENT_HEADER_SIZE..pos + DIRENT_HEADER_SIZE + name_len]
+}
+
+fn has_tail(block: &[u8]) -> bool {
+    let tail = &block[BLOCK_SIZE - TAIL_SIZE..];
+    le32(tail, 0) == 0 && le16(tail, 4) as usize == TAIL_SIZE && tail[6] == 0 && tail[7] == TAIL_FT
+}
@@ -0,0 +1,19 @@
+//! Translation of ext4_rs errors into the errno values of FUSE replies.
+
+use crate::*;
+
+/// ext4_rs reports Linux errno values. Anything outside the table declared
+/// in main.rs is not something callers can act on and becomes EIO.
+pub fn from_ext4(e: Ext4Error) -> i32 {
+    let code = e.error() as i32;
+    match code {
+        EPERM | ENOENT | EINTR | EIO | ENXIO | E2BIG | EBADF | EAGAIN | ENOMEM | EACCES
+        | EFAULT | EBUSY | EEXIST | EXDEV | ENODEV | ENOTDIR | EISDIR | EINVAL | ENFILE
+        | EMFILE | ENOTTY | ETXTBSY | EFBIG | ENOSPC | ESPIPE | EROFS | EMLINK | EPIPE | ERANGE
+        | EDEADLK | ENAMETOOLONG | ENOTEMPTY | ENODATA | EOPNOTSUPP => code,
+        _ => {
+            log::warn!("unexpected ext4_rs error {:?}, reporting EIO", e);
+            EIO
+        }
+    }
+}
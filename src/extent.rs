@@ -0,0 +1,621 @@
+//! Extent trees read and rebuilt directly on disk, for the operations ext4_rs
+//! has no API for, such as preallocation, hole punching and range shifting.
+
+use crate::ondisk::{self, le16, le32, put16, put32, Layout};
+use crate::{Disk, EINVAL, EIO, ENOSPC, ENXIO, EOPNOTSUPP, SEEK_DATA, SEEK_HOLE};
+use ext4_rs::{BlockDevice, Ext4, BLOCK_SIZE};
+
+const EXT4_EXT_MAGIC: u16 = 0xF30A;
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;
+const EXT_INIT_MAX_LEN: u32 = 1 << 15;
+const EXT_UNWRITTEN_MAX_LEN: u32 = EXT_INIT_MAX_LEN - 1;
+const EXT_MAX_DEPTH: usize = 5;
+
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 12;
+const TAIL_SIZE: usize = 4;
+/// Entries in the root node held in `i_block`.
+const ROOT_MAX: usize = (ondisk::I_BLOCK_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+/// Entries in a tree block, leaving room for the checksum tail.
+const NODE_MAX: usize = (BLOCK_SIZE - HEADER_SIZE - TAIL_SIZE) / ENTRY_SIZE;
+
+const BS: u64 = BLOCK_SIZE as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub lblock: u32,
+    pub len: u32,
+    pub pblock: u64,
+    pub unwritten: bool,
+}
+
+impl Extent {
+    pub fn end(&self) -> u32 {
+        self.lblock + self.len
+    }
+
+    fn max_len(&self) -> u32 {
+        if self.unwritten {
+            EXT_UNWRITTEN_MAX_LEN
+        } else {
+            EXT_INIT_MAX_LEN
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let raw_len = le16(buf, 4) as u32;
+        let (len, unwritten) = if raw_len > EXT_INIT_MAX_LEN {
+            (raw_len - EXT_INIT_MAX_LEN, true)
+        } else {
+            (raw_len, false)
+        };
+        Self {
+            lblock: le32(buf, 0),
+            len,
+            pblock: ((le16(buf, 6) as u64) << 32) | le32(buf, 8) as u64,
+            unwritten,
+        }
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        let raw_len = if self.unwritten {
+            self.len + EXT_INIT_MAX_LEN
+        } else {
+            self.len
+        };
+        put32(&mut buf, 0, self.lblock);
+        put16(&mut buf, 4, raw_len as u16);
+        put16(&mut buf, 6, (self.pblock >> 32) as u16);
+        put32(&mut buf, 8, self.pblock as u32);
+        buf
+    }
+}
+
+fn write_header(node: &mut [u8], entries: usize, max: usize, depth: u16) {
+    put16(node, 0, EXT4_EXT_MAGIC);
+    put16(node, 2, entries as u16);
+    put16(node, 4, max as u16);
+    put16(node, 6, depth);
+    put32(node, 8, 0);
+}
+
+/// Turns `i_block` of a raw inode back into an empty extent tree.
+pub fn reset_root(raw: &mut [u8]) {
+    let root = &mut raw[ondisk::I_BLOCK..ondisk::I_BLOCK + ondisk::I_BLOCK_SIZE];
+    root.fill(0);
+    write_header(root, 0, ROOT_MAX, 0);
+    let flags = le32(raw, ondisk::I_FLAGS);
+    put32(raw, ondisk::I_FLAGS, flags | EXT4_EXTENTS_FL);
+}
+
+/// The extents of one inode, sorted by logical block, together with the
+/// tree blocks currently holding them.
+#[derive(Debug, Clone, Default)]
+pub struct ExtentTree {
+    pub extents: Vec<Extent>,
+    nodes: Vec<u64>,
+}
+
+impl ExtentTree {
+    /// Splits the extent straddling `lblock`, so that one starts there.
+    pub fn split_at(&mut self, lblock: u32) {
+        let Some(i) = self
+            .extents
+            .iter()
+            .position(|ext| ext.lblock < lblock && lblock < ext.end())
+        else {
+            return;
+        };
+        let ext = self.extents[i];
+        let head = lblock - ext.lblock;
+        self.extents[i].len = head;
+        self.extents.insert(
+            i + 1,
+            Extent {
+                lblock,
+                len: ext.len - head,
+                pblock: ext.pblock + head as u64,
+                unwritten: ext.unwritten,
+            },
+        );
+    }
+
+    /// Unmaps logical blocks `start..end` and returns the removed pieces.
+    pub fn remove(&mut self, start: u32, end: u32) -> Vec<Extent> {
+        self.split_at(start);
+        self.split_at(end);
+        let (removed, kept) = self
+            .extents
+            .drain(..)
+            .partition(|ext| ext.lblock >= start && ext.end() <= end);
+        self.extents = kept;
+        removed
+    }
+
+    /// Unmapped ranges within logical blocks `start..end`.
+    pub fn holes(&self, start: u32, end: u32) -> Vec<(u32, u32)> {
+        let mut holes = Vec::new();
+        let mut pos = start;
+        for ext in self.overlapping(start, end) {
+            if ext.lblock > pos {
+                holes.push((pos, ext.lblock));
+            }
+            pos = pos.max(ext.end());
+        }
+        if pos < end {
+            holes.push((pos, end));
+        }
+        holes
+    }
+
+    pub fn overlapping(&self, start: u32, end: u32) -> impl Iterator<Item = &Extent> {
+        self.extents
+            .iter()
+            .filter(move |ext| ext.end() > start && ext.lblock < end)
+    }
+
+    /// Marks logical blocks `start..end` as unwritten or written.
+    pub fn set_unwritten(&mut self, start: u32, end: u32, unwritten: bool) {
+        self.split_at(start);
+        self.split_at(end);
+        for ext in &mut self.extents {
+            if ext.lblock >= start && ext.end() <= end {
+                ext.unwritten = unwritten;
+            }
+        }
+    }
+
+    pub fn insert(&mut self, ext: Extent) {
+        let pos = self.extents.partition_point(|e| e.lblock < ext.lblock);
+        self.extents.insert(pos, ext);
+    }
+
+    /// Moves every extent starting at or after `from` by `delta` blocks.
+    pub fn shift(&mut self, from: u32, delta: i64) {
+        for ext in &mut self.extents {
+            if ext.lblock >= from {
+                ext.lblock = (ext.lblock as i64 + delta) as u32;
+            }
+        }
+    }
+
+    /// Physical block following the mapping just before `lblock`, as an
+    /// allocation goal that keeps the file contiguous.
+    pub fn goal(&self, lblock: u32) -> Option<u64> {
+        self.extents
+            .iter()
+            .rev()
+            .find(|ext| ext.lblock < lblock)
+            .map(|ext| ext.pblock + ext.len as u64)
+    }
+
+    /// First block at or after `lblock` holding written data.
+    pub fn next_data(&self, lblock: u32) -> Option<u32> {
+        self.extents
+            .iter()
+            .find(|ext| !ext.unwritten && ext.end() > lblock)
+            .map(|ext| ext.lblock.max(lblock))
+    }
+
+    /// First block at or after `lblock` that is unmapped or unwritten.
+    pub fn next_hole(&self, lblock: u32) -> u32 {
+        let mut pos = lblock;
+        for ext in self.extents.iter().filter(|ext| !ext.unwritten) {
+            if ext.end() <= pos {
+                continue;
+            }
+            if ext.lblock > pos {
+                break;
+            }
+            pos = ext.end();
+        }
+        pos
+    }
+
+    /// Merges neighbours that are contiguous both logically and on disk.
+    fn merge(&mut self) {
+        let mut merged: Vec<Extent> = Vec::with_capacity(self.extents.len());
+        for ext in self.extents.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if last.end() == ext.lblock
+                    && last.pblock + last.len as u64 == ext.pblock
+                    && last.unwritten == ext.unwritten
+                    && last.len + ext.len <= last.max_len()
+                {
+                    last.len += ext.len;
+                    continue;
+                }
+            }
+            merged.push(ext);
+        }
+        self.extents = merged;
+    }
+}
+
+/// Checks the header of a tree node at `level` and returns its entry count
+/// and depth.
+fn check_node(node: &[u8], level: usize) -> Result<(usize, u16), i32> {
+    let entries = le16(node, 2) as usize;
+    let depth = le16(node, 6);
+    if le16(node, 0) != EXT4_EXT_MAGIC
+        || level > EXT_MAX_DEPTH
+        || HEADER_SIZE + entries * ENTRY_SIZE > node.len()
+    {
+        log::error!("corrupted extent node at level {}", level);
+        return Err(EIO);
+    }
+    Ok((entries, depth))
+}
+
+/// The block an index entry points to.
+fn child_block(entry: &[u8]) -> u64 {
+    le32(entry, 4) as u64 | ((le16(entry, 8) as u64) << 32)
+}
+
+/// Extent tree access on top of an opened image.
+pub struct ExtentStore<'a> {
+    pub ext4: &'a Ext4,
+    pub disk: &'a Disk,
+    pub layout: &'a Layout,
+}
+
+impl ExtentStore<'_> {
+    /// Reads the whole extent tree of `ino`. Block-mapped inodes are not
+    /// supported.
+    pub fn load(&self, ino: u32) -> Result<ExtentTree, i32> {
+        let raw = self.layout.read_inode(self.disk, ino);
+        if le32(&raw, ondisk::I_FLAGS) & EXT4_EXTENTS_FL == 0 {
+            return Err(EOPNOTSUPP);
+        }
+
+        let mut tree = ExtentTree::default();
+        let root = &raw[ondisk::I_BLOCK..ondisk::I_BLOCK + ondisk::I_BLOCK_SIZE];
+        self.walk(root, &mut tree, 0)?;
+        tree.extents.sort_by_key(|ext| ext.lblock);
+        Ok(tree)
+    }
+
+    fn walk(&self, node: &[u8], tree: &mut ExtentTree, level: usize) -> Result<(), i32> {
+        let (entries, depth) = check_node(node, level)?;
+        for i in 0..entries {
+            let entry = &node[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+            if depth == 0 {
+                let ext = Extent::decode(entry);
+                if ext.len > 0 {
+                    tree.extents.push(ext);
+                }
+            } else {
+                let child = child_block(entry);
+                tree.nodes.push(child);
+                let buf = self.disk.read_offset(child as usize * BLOCK_SIZE);
+                self.walk(&buf, tree, level + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The extents of `ino` overlapping blocks `first..last`, found by
+    /// descending only into the tree nodes that cover them.
+    pub fn find(&self, ino: u32, first: u32, last: u32) -> Result<Vec<Extent>, i32> {
+        let raw = self.layout.read_inode(self.disk, ino);
+        if le32(&raw, ondisk::I_FLAGS) & EXT4_EXTENTS_FL == 0 {
+            return Err(EOPNOTSUPP);
+        }
+
+        let mut found = Vec::new();
+        let root = &raw[ondisk::I_BLOCK..ondisk::I_BLOCK + ondisk::I_BLOCK_SIZE];
+        self.walk_range(root, first, last, &mut found, 0)?;
+        found.sort_by_key(|ext| ext.lblock);
+        Ok(found)
+    }
+
+    fn walk_range(
+        &self,
+        node: &[u8],
+        first: u32,
+        last: u32,
+        found: &mut Vec<Extent>,
+        level: usize,
+    ) -> Result<(), i32> {
+        let (entries, depth) = check_node(node, level)?;
+        let entry = |i: usize| &node[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        for i in 0..entries {
+            if depth == 0 {
+                let ext = Extent::decode(entry(i));
+                if ext.len > 0 && ext.lblock < last && ext.end() > first {
+                    found.push(ext);
+                }
+                continue;
+            }
+            // A child covers the blocks up to where the next one starts.
+            let start = le32(entry(i), 0);
+            if start >= last {
+                break;
+            }
+            if i + 1 < entries && le32(entry(i + 1), 0) <= first {
+                continue;
+            }
+            let buf = self
+                .disk
+                .read_offset(child_block(entry(i)) as usize * BLOCK_SIZE);
+            self.walk_range(&buf, first, last, found, level + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `tree` back, rebuilding the index levels from scratch, then
+    /// frees the old tree blocks and the data blocks in `freed`.
+    pub fn store(&self, ino: u32, tree: &mut ExtentTree, freed: &[Extent]) -> Result<(), i32> {
+        tree.merge();
+
+        let generation = le32(
+            &self.layout.read_inode(self.disk, ino),
+            ondisk::I_GENERATION,
+        );
+        let mut inode_ref = self.ext4.get_inode_ref(ino);
+        let mut new_nodes = Vec::new();
+
+        // Each level is a list of (first logical block, entry) pairs; pack
+        // them into tree blocks until the top level fits in the inode.
+        let mut level: Vec<(u32, [u8; ENTRY_SIZE])> = tree
+            .extents
+            .iter()
+            .map(|ext| (ext.lblock, ext.encode()))
+            .collect();
+        let mut depth = 0;
+        while level.len() > ROOT_MAX {
+            let mut parents = Vec::new();
+            for chunk in level.chunks(NODE_MAX) {
+                let block = match self.ext4.balloc_alloc_block(&mut inode_ref, None) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        log::error!("failed to allocate extent block for inode {}: {:?}", ino, e);
+                        for &block in &new_nodes {
+                            self.ext4.balloc_free_blocks(&mut inode_ref, block, 1);
+                        }
+                        return Err(ENOSPC);
+                    }
+                };
+                new_nodes.push(block);
+
+                let mut buf = vec![0u8; BLOCK_SIZE];
+                write_header(&mut buf, chunk.len(), NODE_MAX, depth);
+                for (i, (_, entry)) in chunk.iter().enumerate() {
+                    buf[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(entry);
+                }
+                if self.layout.has_metadata_csum() {
+                    let tail = HEADER_SIZE + NODE_MAX * ENTRY_SIZE;
+                    let seed = self.layout.inode_csum_seed(ino, generation);
+                    let csum = ondisk::crc32c(seed, &buf[..tail]);
+                    put32(&mut buf, tail, csum);
+                }
+                self.disk.write_offset(block as usize * BLOCK_SIZE, &buf);
+
+                let mut index = [0u8; ENTRY_SIZE];
+                put32(&mut index, 0, chunk[0].0);
+                put32(&mut index, 4, block as u32);
+                put16(&mut index, 8, (block >> 32) as u16);
+                parents.push((chunk[0].0, index));
+            }
+            level = parents;
+            depth += 1;
+        }
+        self.ext4.write_back_inode(&mut inode_ref);
+
+        let mut raw = self.layout.read_inode(self.disk, ino);
+        let root = &mut raw[ondisk::I_BLOCK..ondisk::I_BLOCK + ondisk::I_BLOCK_SIZE];
+        root.fill(0);
+        write_header(root, level.len(), ROOT_MAX, depth);
+        for (i, (_, entry)) in level.iter().enumerate() {
+            root[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(entry);
+        }
+        self.layout.write_inode(self.disk, ino, &mut raw);
+
+        // Reload so that ext4_rs writes back the new root along with the
+        // block counts it updates.
+        let mut inode_ref = self.ext4.get_inode_ref(ino);
+        for &block in &tree.nodes {
+            self.ext4.balloc_free_blocks(&mut inode_ref, block, 1);
+        }
+        for ext in freed {
+            self.ext4
+                .balloc_free_blocks(&mut inode_ref, ext.pblock, ext.len);
+        }
+        self.ext4.write_back_inode(&mut inode_ref);
+
+        tree.nodes = new_nodes;
+        Ok(())
+    }
+
+    /// Allocates `count` blocks for logical blocks from `lblock` on, as
+    /// contiguous as the allocator allows, and returns them as unwritten
+    /// extents. Nothing stays allocated if it fails.
+    pub fn allocate(
+        &self,
+        ino: u32,
+        lblock: u32,
+        count: u32,
+        goal: Option<u64>,
+    ) -> Result<Vec<Extent>, i32> {
+        let mut inode_ref = self.ext4.get_inode_ref(ino);
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut goal = goal;
+        let mut done = 0;
+
+        while done < count {
+            // One allocator call per run of up to an extent's length.
+            let want = (count - done).min(EXT_UNWRITTEN_MAX_LEN) as usize;
+            let blocks = match self
+                .ext4
+                .balloc_alloc_block_batch(&mut inode_ref, goal, want)
+            {
+                Ok(blocks) if !blocks.is_empty() => blocks,
+                r => {
+                    log::error!(
+                        "failed to allocate data blocks for inode {}: {:?}",
+                        ino,
+                        r.err()
+                    );
+                    self.ext4.write_back_inode(&mut inode_ref);
+                    self.free(ino, &extents);
+                    return Err(ENOSPC);
+                }
+            };
+            for pblock in blocks {
+                match extents.last_mut() {
+                    Some(last)
+                        if last.pblock + last.len as u64 == pblock
+                            && last.len < EXT_UNWRITTEN_MAX_LEN =>
+                    {
+                        last.len += 1
+                    }
+                    _ => extents.push(Extent {
+                        lblock: lblock + done,
+                        len: 1,
+                        pblock,
+                        unwritten: true,
+                    }),
+                }
+                done += 1;
+                goal = Some(pblock + 1);
+            }
+        }
+
+        self.ext4.write_back_inode(&mut inode_ref);
+        Ok(extents)
+    }
+
+    /// Gives back blocks from `allocate` that did not make it into a stored
+    /// tree.
+    pub fn free(&self, ino: u32, extents: &[Extent]) {
+        if extents.is_empty() {
+            return;
+        }
+        let mut inode_ref = self.ext4.get_inode_ref(ino);
+        for ext in extents {
+            self.ext4
+                .balloc_free_blocks(&mut inode_ref, ext.pblock, ext.len);
+        }
+        self.ext4.write_back_inode(&mut inode_ref);
+    }
+
+    pub fn size(&self, ino: u32) -> u64 {
+        ondisk::inode_size(&self.layout.read_inode(self.disk, ino))
+    }
+
+    pub fn set_size(&self, ino: u32, size: u64) {
+        let mut raw = self.layout.read_inode(self.disk, ino);
+        ondisk::set_inode_size(&mut raw, size);
+        self.layout.write_inode(self.disk, ino, &mut raw);
+    }
+
+    /// lseek(2) with SEEK_DATA or SEEK_HOLE. Unwritten extents read as
+    /// zeros, so they count as holes; there is a virtual hole at EOF.
+    pub fn seek(&self, ino: u32, offset: u64, whence: i32) -> Result<u64, i32> {
+        let size = self.size(ino);
+        if offset >= size {
+            return Err(ENXIO);
+        }
+        let tree = match self.load(ino) {
+            Ok(tree) => Some(tree),
+            // Block-mapped files are reported as all data.
+            Err(EOPNOTSUPP) => None,
+            Err(e) => return Err(e),
+        };
+
+        let lblock = (offset / BS) as u32;
+        match whence {
+            SEEK_DATA => {
+                let Some(tree) = tree else {
+                    return Ok(offset);
+                };
+                let data = tree.next_data(lblock).ok_or(ENXIO)?;
+                let pos = (data as u64 * BS).max(offset);
+                if pos >= size {
+                    return Err(ENXIO);
+                }
+                Ok(pos)
+            }
+            SEEK_HOLE => {
+                let Some(tree) = tree else {
+                    return Ok(size);
+                };
+                let hole = tree.next_hole(lblock) as u64 * BS;
+                Ok(hole.max(offset).min(size))
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Zeroes bytes `start..end` of the file, which must lie within one
+    /// block. Holes and unwritten blocks already read as zeros.
+    pub fn zero_bytes(&self, tree: &ExtentTree, start: u64, end: u64) {
+        let lblock = (start / BS) as u32;
+        let Some(ext) = tree.overlapping(lblock, lblock + 1).next() else {
+            return;
+        };
+        if ext.unwritten {
+            return;
+        }
+
+        let offset = (ext.pblock + (lblock - ext.lblock) as u64) as usize * BLOCK_SIZE;
+        let mut buf = self.disk.read_offset(offset);
+        buf[(start % BS) as usize..][..(end - start) as usize].fill(0);
+        self.disk.write_offset(offset, &buf);
+    }
+
+    /// Zeroes the parts of `data`, read at `offset`, that fall in unwritten
+    /// extents, which must read as zeros whatever the blocks contain.
+    pub fn mask_unwritten(&self, ino: u32, offset: u64, data: &mut [u8]) -> Result<(), i32> {
+        let end = offset + data.len() as u64;
+        let first = (offset / BS) as u32;
+        let last = end.div_ceil(BS) as u32;
+        let extents = match self.find(ino, first, last) {
+            Ok(extents) => extents,
+            Err(EOPNOTSUPP) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for ext in extents.iter().filter(|ext| ext.unwritten) {
+            let start = (ext.lblock as u64 * BS).max(offset);
+            let stop = (ext.end() as u64 * BS).min(end);
+            data[(start - offset) as usize..(stop - offset) as usize].fill(0);
+        }
+        Ok(())
+    }
+
+    /// Prepares a write of `len` bytes at `offset`: unwritten blocks in the
+    /// range are zeroed on disk and marked written, so that the bytes of a
+    /// partially written block around the new data read back as zeros.
+    pub fn prepare_write(&self, ino: u32, offset: u64, len: u64) -> Result<(), i32> {
+        let first = (offset / BS) as u32;
+        let last = (offset + len).div_ceil(BS) as u32;
+        // Most writes find nothing to do without loading the whole tree.
+        match self.find(ino, first, last) {
+            Ok(extents) if extents.iter().any(|ext| ext.unwritten) => {}
+            Ok(_) | Err(EOPNOTSUPP) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let mut tree = self.load(ino)?;
+        let unwritten: Vec<Extent> = tree
+            .overlapping(first, last)
+            .filter(|ext| ext.unwritten)
+            .copied()
+            .collect();
+        if unwritten.is_empty() {
+            return Ok(());
+        }
+
+        let zeros = vec![0u8; BLOCK_SIZE];
+        for ext in &unwritten {
+            for lblock in ext.lblock.max(first)..ext.end().min(last) {
+                let pblock = ext.pblock + (lblock - ext.lblock) as u64;
+                self.disk.write_offset(pblock as usize * BLOCK_SIZE, &zeros);
+            }
+        }
+        tree.set_unwritten(first, last, false);
+        self.store(ino, &mut tree, &[])
+    }
+}
@@ -0,0 +1,36 @@
+//! `ext4libtest --extents <path>`: prints how a file in the image is laid
+//! out, in the columns of `filefrag -v`. The kernel answers FIEMAP and bmap
+//! itself on FUSE mounts and never asks the filesystem, so the extent map
+//! is read from the image instead.
+
+use crate::extent::ExtentTree;
+use std::fmt::Write;
+
+/// One line per extent, in blocks: logical range, physical range, length
+/// and flags. Holes between extents are left out, as filefrag does.
+pub fn describe(tree: &ExtentTree) -> String {
+    let mut out = String::from(" ext:     logical_offset:        physical_offset: length: flags\n");
+    let count = tree.extents.len();
+    for (i, ext) in tree.extents.iter().enumerate() {
+        let mut flags = Vec::new();
+        if ext.unwritten {
+            flags.push("unwritten");
+        }
+        if i + 1 == count {
+            flags.push("last");
+        }
+        let _ = writeln!(
+            out,
+            "{:4}: {:>8}..{:>8}: {:>10}..{:>10}: {:>6}: {}",
+            i,
+            ext.lblock,
+            ext.end() - 1,
+            ext.pblock,
+            ext.pblock + ext.len as u64 - 1,
+            ext.len,
+            flags.join(",")
+        );
+    }
+    let _ = writeln!(out, "{} extents, {} tree blocks", count, tree.nodes.len());
+    out
+}
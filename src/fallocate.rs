@@ -0,0 +1,224 @@
+//! fallocate(2) modes on top of the extent tree.
+
+use crate::extent::{Extent, ExtentStore, ExtentTree};
+use crate::{EFBIG, EINVAL, EOPNOTSUPP};
+use ext4_rs::BLOCK_SIZE;
+
+pub const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+pub const FALLOC_FL_COLLAPSE_RANGE: i32 = 0x08;
+pub const FALLOC_FL_ZERO_RANGE: i32 = 0x10;
+pub const FALLOC_FL_INSERT_RANGE: i32 = 0x20;
+
+const BS: u64 = BLOCK_SIZE as u64;
+/// Largest file size addressable with 32-bit logical block numbers.
+const MAX_FILE_SIZE: u64 = (u32::MAX as u64) * BS;
+
+pub fn fallocate(
+    store: &ExtentStore,
+    ino: u32,
+    offset: u64,
+    len: u64,
+    mode: i32,
+) -> Result<(), i32> {
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    let end = offset.checked_add(len).ok_or(EFBIG)?;
+    if end > MAX_FILE_SIZE {
+        return Err(EFBIG);
+    }
+
+    let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+    match mode & !FALLOC_FL_KEEP_SIZE {
+        0 => preallocate(store, ino, offset, end, keep_size),
+        FALLOC_FL_PUNCH_HOLE if keep_size => punch_hole(store, ino, offset, end),
+        FALLOC_FL_ZERO_RANGE => zero_range(store, ino, offset, end, keep_size),
+        FALLOC_FL_COLLAPSE_RANGE if !keep_size => collapse_range(store, ino, offset, len),
+        FALLOC_FL_INSERT_RANGE if !keep_size => insert_range(store, ino, offset, len),
+        // Punching a hole must not change the size.
+        FALLOC_FL_PUNCH_HOLE => Err(EOPNOTSUPP),
+        FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_INSERT_RANGE => Err(EINVAL),
+        _ => Err(EOPNOTSUPP),
+    }
+}
+
+/// Maps every hole in blocks `start..end` to freshly allocated unwritten
+/// extents, which are returned to be freed if the tree cannot be stored.
+fn fill_holes(
+    store: &ExtentStore,
+    ino: u32,
+    tree: &mut ExtentTree,
+    start: u32,
+    end: u32,
+) -> Result<Vec<Extent>, i32> {
+    let mut allocated = Vec::new();
+    for (hole_start, hole_end) in tree.holes(start, end) {
+        let extents = match store.allocate(
+            ino,
+            hole_start,
+            hole_end - hole_start,
+            tree.goal(hole_start),
+        ) {
+            Ok(extents) => extents,
+            Err(e) => {
+                store.free(ino, &allocated);
+                return Err(e);
+            }
+        };
+        for ext in extents {
+            tree.insert(ext);
+            allocated.push(ext);
+        }
+    }
+    Ok(allocated)
+}
+
+/// Stores a tree `fill_holes` added `allocated` to, freeing them again if
+/// that fails.
+fn store_filled(
+    store: &ExtentStore,
+    ino: u32,
+    tree: &mut ExtentTree,
+    allocated: &[Extent],
+) -> Result<(), i32> {
+    store
+        .store(ino, tree, &[])
+        .inspect_err(|_| store.free(ino, allocated))
+}
+
+fn first_block(offset: u64) -> u32 {
+    (offset / BS) as u32
+}
+
+fn end_block(end: u64) -> u32 {
+    end.div_ceil(BS) as u32
+}
+
+/// Zeroes the partial blocks at both edges of `offset..end` and returns the
+/// whole blocks in between.
+fn zero_edges(store: &ExtentStore, tree: &ExtentTree, offset: u64, end: u64) -> (u32, u32) {
+    let start = offset.div_ceil(BS);
+    let stop = end / BS;
+    if start > stop {
+        // Both edges fall in the same block.
+        store.zero_bytes(tree, offset, end);
+        return (0, 0);
+    }
+    if offset % BS != 0 {
+        store.zero_bytes(tree, offset, start * BS);
+    }
+    if end % BS != 0 {
+        store.zero_bytes(tree, stop * BS, end);
+    }
+    (start as u32, stop as u32)
+}
+
+fn preallocate(
+    store: &ExtentStore,
+    ino: u32,
+    offset: u64,
+    end: u64,
+    keep_size: bool,
+) -> Result<(), i32> {
+    let mut tree = store.load(ino)?;
+    let allocated = fill_holes(store, ino, &mut tree, first_block(offset), end_block(end))?;
+    store_filled(store, ino, &mut tree, &allocated)?;
+
+    if !keep_size && end > store.size(ino) {
+        store.set_size(ino, end);
+    }
+    Ok(())
+}
+
+fn punch_hole(store: &ExtentStore, ino: u32, offset: u64, end: u64) -> Result<(), i32> {
+    let size = store.size(ino);
+    if offset >= size {
+        return Ok(());
+    }
+    // As in ext4, the hole stops at the end of the block holding EOF;
+    // preallocated blocks past it stay.
+    let end = end.min(size.next_multiple_of(BS));
+
+    let mut tree = store.load(ino)?;
+    let (start, stop) = zero_edges(store, &tree, offset, end);
+    if start >= stop {
+        return Ok(());
+    }
+    let freed = tree.remove(start, stop);
+    store.store(ino, &mut tree, &freed)
+}
+
+fn zero_range(
+    store: &ExtentStore,
+    ino: u32,
+    offset: u64,
+    end: u64,
+    keep_size: bool,
+) -> Result<(), i32> {
+    let mut tree = store.load(ino)?;
+    let allocated = fill_holes(store, ino, &mut tree, first_block(offset), end_block(end))?;
+
+    let (start, stop) = zero_edges(store, &tree, offset, end);
+    if start < stop {
+        tree.set_unwritten(start, stop, true);
+    }
+    store_filled(store, ino, &mut tree, &allocated)?;
+
+    if !keep_size && end > store.size(ino) {
+        store.set_size(ino, end);
+    }
+    Ok(())
+}
+
+fn check_aligned(offset: u64, len: u64) -> Result<(), i32> {
+    if offset % BS != 0 || len % BS != 0 {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+fn collapse_range(store: &ExtentStore, ino: u32, offset: u64, len: u64) -> Result<(), i32> {
+    check_aligned(offset, len)?;
+    let size = store.size(ino);
+    if offset + len >= size {
+        return Err(EINVAL);
+    }
+
+    let start = first_block(offset);
+    let stop = first_block(offset + len);
+    let mut tree = store.load(ino)?;
+    let freed: Vec<Extent> = tree.remove(start, stop);
+    tree.shift(stop, -((stop - start) as i64));
+    store.store(ino, &mut tree, &freed)?;
+
+    store.set_size(ino, size - len);
+    Ok(())
+}
+
+fn insert_range(store: &ExtentStore, ino: u32, offset: u64, len: u64) -> Result<(), i32> {
+    check_aligned(offset, len)?;
+    let size = store.size(ino);
+    if offset >= size {
+        return Err(EINVAL);
+    }
+    if size + len > MAX_FILE_SIZE {
+        return Err(EFBIG);
+    }
+
+    let start = first_block(offset);
+    let mut tree = store.load(ino)?;
+    if tree
+        .extents
+        .last()
+        .is_some_and(|ext| ext.end() as u64 + len / BS > u32::MAX as u64)
+    {
+        return Err(EFBIG);
+    }
+    tree.split_at(start);
+    tree.shift(start, (len / BS) as i64);
+    store.store(ino, &mut tree, &[])?;
+
+    store.set_size(ino, size + len);
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! The superblock feature check done before the image is handed to
+//! ext4_rs. An unknown incompat feature means ext4_rs would misread the
+//! image, so it is not mounted at all; an unknown ro_compat feature means
+//! it would write the image wrongly, so it is mounted read-only.
+
+use crate::ondisk::{
+    le32, EXT4_FEATURE_INCOMPAT_64BIT, EXT4_FEATURE_INCOMPAT_CSUM_SEED,
+    EXT4_FEATURE_RO_COMPAT_METADATA_CSUM,
+};
+
+const S_FEATURE_INCOMPAT: usize = 0x60;
+const S_FEATURE_RO_COMPAT: usize = 0x64;
+
+const INCOMPAT_NAMES: &[(u32, &str)] = &[
+    (0x1, "compression"),
+    (0x2, "filetype"),
+    (0x4, "needs_recovery"),
+    (0x8, "journal_dev"),
+    (0x10, "meta_bg"),
+    (0x40, "extent"),
+    (0x80, "64bit"),
+    (0x100, "mmp"),
+    (0x200, "flex_bg"),
+    (0x400, "ea_inode"),
+    (0x1000, "dirdata"),
+    (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"),
+    (0x8000, "inline_data"),
+    (0x10000, "encrypt"),
+    (0x20000, "casefold"),
+];
+
+const RO_COMPAT_NAMES: &[(u32, &str)] = &[
+    (0x1, "sparse_super"),
+    (0x2, "large_file"),
+    (0x8, "huge_file"),
+    (0x10, "uninit_bg"),
+    (0x20, "dir_nlink"),
+    (0x40, "extra_isize"),
+    (0x100, "quota"),
+    (0x200, "bigalloc"),
+    (0x400, "metadata_csum"),
+    (0x1000, "read-only"),
+    (0x2000, "project"),
+    (0x4000, "shared_blocks"),
+    (0x8000, "verity"),
+    (0x10000, "orphan_present"),
+];
+
+const EXT4_FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const EXT4_FEATURE_INCOMPAT_EXTENTS: u32 = 0x40;
+const EXT4_FEATURE_INCOMPAT_FLEX_BG: u32 = 0x200;
+
+const EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const EXT4_FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const EXT4_FEATURE_RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const EXT4_FEATURE_RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+
+/// Incompat features ext4_rs reads correctly.
+const SUPPORTED_INCOMPAT: u32 = EXT4_FEATURE_INCOMPAT_FILETYPE
+    | EXT4_FEATURE_INCOMPAT_EXTENTS
+    | EXT4_FEATURE_INCOMPAT_64BIT
+    | EXT4_FEATURE_INCOMPAT_FLEX_BG
+    | EXT4_FEATURE_INCOMPAT_CSUM_SEED;
+
+/// Ro_compat features ext4_rs and ext4libtest keep up to date on writes.
+const SUPPORTED_RO_COMPAT: u32 = EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER
+    | EXT4_FEATURE_RO_COMPAT_LARGE_FILE
+    | EXT4_FEATURE_RO_COMPAT_HUGE_FILE
+    | EXT4_FEATURE_RO_COMPAT_DIR_NLINK
+    | EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE
+    | EXT4_FEATURE_RO_COMPAT_METADATA_CSUM;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub incompat: u32,
+    pub ro_compat: u32,
+}
+
+impl Features {
+    pub fn from_superblock(sb: &[u8]) -> Self {
+        Self {
+            incompat: le32(sb, S_FEATURE_INCOMPAT),
+            ro_compat: le32(sb, S_FEATURE_RO_COMPAT),
+        }
+    }
+
+    /// Incompat features that keep the image from being mounted.
+    pub fn unsupported_incompat(&self) -> u32 {
+        self.incompat & !SUPPORTED_INCOMPAT
+    }
+
+    /// Ro_compat features that keep the image from being mounted
+    /// read-write.
+    pub fn unsupported_ro_compat(&self) -> u32 {
+        self.ro_compat & !SUPPORTED_RO_COMPAT
+    }
+
+    /// Logs the features of the image and those that limit the mount.
+    pub fn report(&self) {
+        log::info!(
+            "incompat features: {}",
+            names(self.incompat, INCOMPAT_NAMES)
+        );
+        log::info!(
+            "ro_compat features: {}",
+            names(self.ro_compat, RO_COMPAT_NAMES)
+        );
+        let incompat = self.unsupported_incompat();
+        if incompat != 0 {
+            log::error!(
+                "cannot mount: unsupported incompat features {}",
+                names(incompat, INCOMPAT_NAMES)
+            );
+        }
+        let ro_compat = self.unsupported_ro_compat();
+        if ro_compat != 0 {
+            log::warn!(
+                "read-write mount blocked by unsupported ro_compat features {}",
+                names(ro_compat, RO_COMPAT_NAMES)
+            );
+        }
+    }
+}
+
+/// The names of the feature bits in `bits`, as e2fsprogs prints them,
+/// with unnamed ones in hex.
+fn names(bits: u32, table: &[(u32, &str)]) -> String {
+    let names: Vec<String> = (0..32)
+        .map(|i| 1 << i)
+        .filter(|bit| bits & bit != 0)
+        .map(|bit| match table.iter().find(|(b, _)| *b == bit) {
+            Some((_, name)) => name.to_string(),
+            None => format!("{:#x}", bit),
+        })
+        .collect();
+    if names.is_empty() {
+        "(none)".to_string()
+    } else {
+        names.join(" ")
+    }
+}
@@ -0,0 +1,90 @@
+//! ext4 inode flags as lsattr/chattr see them through FS_IOC_GETFLAGS and
+//! FS_IOC_SETFLAGS, and the BSD chflags(2) bits that map onto them.
+
+use crate::ondisk::{le32, put32, I_FLAGS};
+use crate::{EOPNOTSUPP, EPERM};
+
+pub const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+pub const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+pub const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+pub const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+
+pub const EXT4_SECRM_FL: u32 = 0x0000_0001;
+pub const EXT4_UNRM_FL: u32 = 0x0000_0002;
+pub const EXT4_SYNC_FL: u32 = 0x0000_0008;
+pub const EXT4_IMMUTABLE_FL: u32 = 0x0000_0010;
+pub const EXT4_APPEND_FL: u32 = 0x0000_0020;
+pub const EXT4_NODUMP_FL: u32 = 0x0000_0040;
+pub const EXT4_NOATIME_FL: u32 = 0x0000_0080;
+pub const EXT4_DIRSYNC_FL: u32 = 0x0001_0000;
+pub const EXT4_TOPDIR_FL: u32 = 0x0002_0000;
+pub const EXT4_PROJINHERIT_FL: u32 = 0x2000_0000;
+
+/// Flags reported to lsattr.
+const EXT4_FL_USER_VISIBLE: u32 = 0x705B_DFFF;
+/// Flags chattr may change here. The rest either need data conversion
+/// ext4_rs cannot do (compression, extents, inline data, encryption) or
+/// are maintained by the filesystem itself.
+const SETTABLE: u32 = EXT4_SECRM_FL
+    | EXT4_UNRM_FL
+    | EXT4_SYNC_FL
+    | EXT4_IMMUTABLE_FL
+    | EXT4_APPEND_FL
+    | EXT4_NODUMP_FL
+    | EXT4_NOATIME_FL
+    | EXT4_DIRSYNC_FL
+    | EXT4_TOPDIR_FL
+    | EXT4_PROJINHERIT_FL;
+
+const UF_NODUMP: u32 = 0x0000_0001;
+const UF_IMMUTABLE: u32 = 0x0000_0002;
+const UF_APPEND: u32 = 0x0000_0004;
+const SF_IMMUTABLE: u32 = 0x0002_0000;
+const SF_APPEND: u32 = 0x0004_0000;
+
+pub fn get(raw: &[u8]) -> u32 {
+    le32(raw, I_FLAGS)
+}
+
+pub fn set(raw: &mut [u8], flags: u32) {
+    put32(raw, I_FLAGS, flags);
+}
+
+/// What FS_IOC_GETFLAGS reports for `flags`.
+pub fn visible(flags: u32) -> u32 {
+    flags & EXT4_FL_USER_VISIBLE
+}
+
+/// Checks a FS_IOC_SETFLAGS request against the current flags, as
+/// `ext4_ioctl_setflags` does, and returns the new on-disk flags. Only a
+/// privileged caller may touch the immutable and append-only flags, and an
+/// immutable inode must be made mutable before anything else changes.
+pub fn apply(old: u32, new: u32, privileged: bool) -> Result<u32, i32> {
+    let changed = (old ^ new) & EXT4_FL_USER_VISIBLE;
+    if changed & !SETTABLE != 0 {
+        return Err(EOPNOTSUPP);
+    }
+    if changed & (EXT4_IMMUTABLE_FL | EXT4_APPEND_FL) != 0 && !privileged {
+        return Err(EPERM);
+    }
+    if old & new & EXT4_IMMUTABLE_FL != 0 && changed != 0 {
+        return Err(EPERM);
+    }
+    Ok((old & !SETTABLE) | (new & SETTABLE))
+}
+
+/// Translates the chflags(2) bits of a setattr into ext4 flags, keeping
+/// the flags chflags has no bit for.
+pub fn from_bsd(old: u32, bsd: u32) -> u32 {
+    let mut flags = old & !(EXT4_NODUMP_FL | EXT4_IMMUTABLE_FL | EXT4_APPEND_FL);
+    if bsd & UF_NODUMP != 0 {
+        flags |= EXT4_NODUMP_FL;
+    }
+    if bsd & (UF_IMMUTABLE | SF_IMMUTABLE) != 0 {
+        flags |= EXT4_IMMUTABLE_FL;
+    }
+    if bsd & (UF_APPEND | SF_APPEND) != 0 {
+        flags |= EXT4_APPEND_FL;
+    }
+    flags
+}
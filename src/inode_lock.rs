@@ -0,0 +1,94 @@
+//! Per-inode reader/writer locks. An operation takes all the inodes it
+//! touches at once, so two operations can never each hold an inode the
+//! other waits for.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+}
+
+#[derive(Default)]
+pub struct InodeLocks {
+    held: Mutex<HashMap<u64, State>>,
+    released: Condvar,
+}
+
+impl InodeLocks {
+    pub fn shared(&self, ino: u64) -> InodeGuard<'_> {
+        self.lock(&[ino], &[])
+    }
+
+    pub fn exclusive(&self, inos: &[u64]) -> InodeGuard<'_> {
+        self.lock(&[], inos)
+    }
+
+    /// Waits until `shared` can be read and `exclusive` written, then
+    /// takes them all. An inode in both lists is taken exclusively.
+    pub fn lock(&self, shared: &[u64], exclusive: &[u64]) -> InodeGuard<'_> {
+        let mut exclusive = exclusive.to_vec();
+        exclusive.sort_unstable();
+        exclusive.dedup();
+        let mut shared: Vec<u64> = shared
+            .iter()
+            .copied()
+            .filter(|ino| !exclusive.contains(ino))
+            .collect();
+        shared.sort_unstable();
+        shared.dedup();
+
+        let mut held = self.held.lock().unwrap();
+        loop {
+            let readable = shared
+                .iter()
+                .all(|ino| held.get(ino).map_or(true, |s| !s.writer));
+            let writable = exclusive
+                .iter()
+                .all(|ino| held.get(ino).map_or(true, |s| !s.writer && s.readers == 0));
+            if readable && writable {
+                break;
+            }
+            held = self.released.wait(held).unwrap();
+        }
+        for &ino in &shared {
+            held.entry(ino).or_default().readers += 1;
+        }
+        for &ino in &exclusive {
+            held.entry(ino).or_default().writer = true;
+        }
+
+        InodeGuard {
+            locks: self,
+            shared,
+            exclusive,
+        }
+    }
+}
+
+/// Releases its inodes when dropped.
+pub struct InodeGuard<'a> {
+    locks: &'a InodeLocks,
+    shared: Vec<u64>,
+    exclusive: Vec<u64>,
+}
+
+impl Drop for InodeGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap();
+        for ino in &self.shared {
+            if let Some(state) = held.get_mut(ino) {
+                state.readers -= 1;
+            }
+        }
+        for ino in &self.exclusive {
+            if let Some(state) = held.get_mut(ino) {
+                state.writer = false;
+            }
+        }
+        held.retain(|_, state| state.writer || state.readers > 0);
+        self.locks.released.notify_all();
+    }
+}
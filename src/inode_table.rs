@@ -0,0 +1,42 @@
+//! The kernel's references to inodes. Each entry reply hands the kernel a
+//! reference it gives back with forget; an inode unlinked while it still
+//! has some stays allocated, on the orphan list, until the last is gone.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct InodeTable {
+    /// Inode number to its lookup count, for inodes the kernel knows.
+    counts: Mutex<HashMap<u64, u64>>,
+}
+
+impl InodeTable {
+    /// Records one more reference, for an entry about to be replied.
+    pub fn lookup(&self, ino: u64) {
+        *self.counts.lock().unwrap().entry(ino).or_default() += 1;
+    }
+
+    /// Drops `nlookup` references and returns whether none are left.
+    pub fn forget(&self, ino: u64, nlookup: u64) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let Some(count) = counts.get_mut(&ino) else {
+            return true;
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return false;
+        }
+        counts.remove(&ino);
+        true
+    }
+
+    /// Every inode the kernel references.
+    pub fn inodes(&self) -> Vec<u64> {
+        self.counts.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_referenced(&self, ino: u64) -> bool {
+        self.counts.lock().unwrap().contains_key(&ino)
+    }
+}
@@ -0,0 +1,193 @@
+//! In-process byte-range lock manager for fcntl(2) and flock(2) locks on
+//! the mount. The kernel passes flock requests as whole-file locks owned by
+//! the open file, so both kinds share one table per inode and conflict with
+//! each other, as on BSD.
+
+use crate::{EAGAIN, EDEADLK, EINTR, EINVAL, F_RDLCK, F_UNLCK, F_WRLCK};
+use fuser::ReplyEmpty;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    pub start: u64,
+    /// Last byte covered, inclusive.
+    pub end: u64,
+    pub typ: i32,
+    pub owner: u64,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// A blocked F_SETLKW, answered once its lock can be granted.
+struct Waiter {
+    ino: u64,
+    lock: Lock,
+    reply: ReplyEmpty,
+}
+
+#[derive(Default)]
+pub struct LockManager {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter>,
+}
+
+impl LockManager {
+    /// F_GETLK: the first lock held by another owner that would block `lock`.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .copied()
+    }
+
+    /// F_SETLK: acquires, converts or releases `lock`, or fails with EAGAIN.
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<(), i32> {
+        if !matches!(lock.typ, F_RDLCK | F_WRLCK | F_UNLCK) || lock.start > lock.end {
+            return Err(EINVAL);
+        }
+        if lock.typ != F_UNLCK && self.conflict(ino, &lock).is_some() {
+            return Err(EAGAIN);
+        }
+
+        self.apply(ino, lock);
+        self.wake();
+        Ok(())
+    }
+
+    /// Replaces the owner's locks over the range of `lock`, merging with
+    /// neighbours of the same type.
+    fn apply(&mut self, ino: u64, lock: Lock) {
+        let held = self.locks.entry(ino).or_default();
+        let mut new = lock;
+        let mut kept = Vec::with_capacity(held.len() + 1);
+        for l in held.drain(..) {
+            if l.owner != lock.owner {
+                kept.push(l);
+                continue;
+            }
+            // Same type, overlapping or adjacent: merge into the new lock.
+            if l.typ == new.typ
+                && l.start <= new.end.saturating_add(1)
+                && new.start <= l.end.saturating_add(1)
+            {
+                new.start = new.start.min(l.start);
+                new.end = new.end.max(l.end);
+                continue;
+            }
+            if !l.overlaps(&lock) {
+                kept.push(l);
+                continue;
+            }
+            // Keep the parts of the old lock outside the new range.
+            if l.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..l
+                });
+            }
+            if l.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..l
+                });
+            }
+        }
+        if new.typ != F_UNLCK {
+            kept.push(new);
+        }
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *held = kept;
+        }
+    }
+
+    /// F_SETLKW: like `set`, but queues the request until it can be granted
+    /// instead of failing.
+    pub fn set_wait(&mut self, ino: u64, lock: Lock, reply: ReplyEmpty) {
+        match self.set(ino, lock) {
+            Err(EAGAIN) if self.would_deadlock(ino, &lock) => reply.error(EDEADLK),
+            Err(EAGAIN) => self.waiters.push(Waiter { ino, lock, reply }),
+            Err(e) => reply.error(e),
+            Ok(()) => reply.ok(),
+        }
+    }
+
+    /// Drops every lock and pending request of `owner` on `ino`, as on the
+    /// last close of a file by a process.
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        if let Some(held) = self.locks.get_mut(&ino) {
+            held.retain(|l| l.owner != owner);
+            if held.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+
+        let (cancelled, waiting): (Vec<_>, Vec<_>) = self
+            .waiters
+            .drain(..)
+            .partition(|w| w.ino == ino && w.lock.owner == owner);
+        self.waiters = waiting;
+        for waiter in cancelled {
+            waiter.reply.error(EINTR);
+        }
+
+        self.wake();
+    }
+
+    /// Grants queued requests that no longer conflict, oldest first.
+    fn wake(&mut self) {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let (ino, lock) = (self.waiters[i].ino, self.waiters[i].lock);
+            if self.conflict(ino, &lock).is_some() {
+                i += 1;
+                continue;
+            }
+            let waiter = self.waiters.remove(i);
+            self.apply(ino, lock);
+            waiter.reply.ok();
+            // Granting may convert the owner's other locks to shared ones,
+            // which can unblock earlier waiters.
+            i = 0;
+        }
+    }
+
+    /// Whether waiting for `lock` would close a cycle of owners waiting on
+    /// each other.
+    fn would_deadlock(&self, ino: u64, lock: &Lock) -> bool {
+        let mut seen = HashSet::new();
+        let mut pending = vec![(ino, *lock)];
+        while let Some((ino, wanted)) = pending.pop() {
+            let Some(held) = self.locks.get(&ino) else {
+                continue;
+            };
+            for blocker in held.iter().filter(|l| l.conflicts(&wanted)) {
+                if blocker.owner == lock.owner {
+                    return true;
+                }
+                if seen.insert(blocker.owner) {
+                    pending.extend(
+                        self.waiters
+                            .iter()
+                            .filter(|w| w.lock.owner == blocker.owner)
+                            .map(|w| (w.ino, w.lock)),
+                    );
+                }
+            }
+        }
+        false
+    }
+}
@@ -1,19 +1,76 @@
 use ext4_rs::*;
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyWrite, Request, TimeOrNow,
+    consts::{
+        FUSE_ASYNC_READ, FUSE_DO_READDIRPLUS, FUSE_FLOCK_LOCKS, FUSE_HAS_IOCTL_DIR,
+        FUSE_POSIX_LOCKS, FUSE_READDIRPLUS_AUTO, FUSE_SPLICE_MOVE, FUSE_SPLICE_READ, FUSE_SPLICE_WRITE,
+        FUSE_WRITEBACK_CACHE, FUSE_WRITE_CACHE,
+    },
+    FileAttr, FileType, KernelConfig, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
+    ReplyOpen, ReplyWrite, ReplyXattr, TimeOrNow,
 };
 use log::{Level, LevelFilter, Metadata, Record};
 use std::{
-    ffi::OsStr,
-    fs::OpenOptions,
-    io::{Read, Seek, Write},
+    ffi::{c_int, OsStr, OsString},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::FileExt,
+    },
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 extern crate alloc;
 use alloc::sync::Arc;
 
+mod acl;
+mod atime;
+mod config;
+mod copy;
+mod credentials;
+mod dir;
+mod dirent;
+mod errno;
+mod extent;
+mod extent_map;
+mod fallocate;
+mod features;
+mod iflags;
+mod inode_lock;
+mod inode_table;
+mod lock;
+mod notify;
+mod ondisk;
+mod orphan;
+mod pool;
+mod server;
+mod superblock;
+mod xattr;
+
+use acl::Acl;
+use atime::{LazyTimes, LAZYTIME_MAX_AGE};
+use config::Config;
+use credentials::{Caller, Credentials};
+use dir::{DirEntry, DirHandles};
+use extent::{ExtentStore, EXT4_EXTENTS_FL};
+use iflags::{EXT4_APPEND_FL, EXT4_IMMUTABLE_FL, EXT4_NOATIME_FL};
+use inode_lock::{InodeGuard, InodeLocks};
+use inode_table::InodeTable;
+use lock::{Lock, LockManager};
+use notify::Notify;
+use ondisk::{InodeTime, Layout};
+use server::Server;
+use superblock::SuperblockFields;
+use xattr::{
+    XattrStore, XATTR_INDEX_POSIX_ACL_ACCESS, XATTR_INDEX_POSIX_ACL_DEFAULT, XATTR_INDEX_USER,
+};
+
 macro_rules! with_color {
     ($color_code:expr, $($arg:tt)*) => {{
         format_args!("\u{1B}[{}m{}\u{1B}[m", $color_code as u8, format_args!($($arg)*))
@@ -92,6 +149,11 @@ pub const EMLINK: i32 = 31;
 pub const EPIPE: i32 = 32;
 pub const EDOM: i32 = 33;
 pub const ERANGE: i32 = 34;
+pub const EDEADLK: i32 = 35;
+pub const ENAMETOOLONG: i32 = 36;
+pub const ENOTEMPTY: i32 = 39;
+pub const ENODATA: i32 = 61;
+pub const EOPNOTSUPP: i32 = 95;
 pub const EWOULDBLOCK: i32 = EAGAIN;
 
 pub const S_IFIFO: u32 = 4096;
@@ -102,6 +164,9 @@ pub const S_IFREG: u32 = 32768;
 pub const S_IFLNK: u32 = 40960;
 pub const S_IFSOCK: u32 = 49152;
 pub const S_IFMT: u32 = 61440;
+pub const S_ISUID: u32 = 2048;
+pub const S_ISGID: u32 = 1024;
+pub const S_ISVTX: u32 = 512;
 pub const S_IRWXU: u32 = 448;
 pub const S_IXUSR: u32 = 64;
 pub const S_IWUSR: u32 = 128;
@@ -118,6 +183,19 @@ pub const F_OK: i32 = 0;
 pub const R_OK: i32 = 4;
 pub const W_OK: i32 = 2;
 pub const X_OK: i32 = 1;
+pub const O_ACCMODE: i32 = 3;
+pub const O_RDONLY: i32 = 0;
+pub const O_WRONLY: i32 = 1;
+pub const O_RDWR: i32 = 2;
+pub const O_TRUNC: i32 = 512;
+pub const O_APPEND: i32 = 1024;
+pub const RENAME_NOREPLACE: u32 = 1;
+pub const RENAME_EXCHANGE: u32 = 2;
+pub const SEEK_DATA: i32 = 3;
+pub const SEEK_HOLE: i32 = 4;
+pub const F_RDLCK: i32 = 0;
+pub const F_WRLCK: i32 = 1;
+pub const F_UNLCK: i32 = 2;
 pub const STDIN_FILENO: i32 = 0;
 pub const STDOUT_FILENO: i32 = 1;
 pub const STDERR_FILENO: i32 = 2;
@@ -133,527 +211,2743 @@ pub const SIGPIPE: i32 = 13;
 pub const SIGALRM: i32 = 14;
 pub const SIGTERM: i32 = 15;
 
-const TTL: Duration = Duration::from_secs(1); // 1 second
+/// How often the image is checked for writes from outside the mount.
+const IMAGE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest file an extent tree can map: 2^32 - 1 blocks.
+const MAX_FILE_SIZE: u64 = ((1 << 32) - 1) * BLOCK_SIZE as u64;
+
+/// Most links an inode can have; directories past it count 1.
+const EXT4_LINK_MAX: u16 = 65000;
+
+/// Timestamps a newly created inode starts with.
+const NEW_INODE_TIMES: [InodeTime; 4] = [
+    InodeTime::Access,
+    InodeTime::Modify,
+    InodeTime::Change,
+    InodeTime::Create,
+];
+
+const IMAGE_PATH: &str = "ex4.img";
 
 #[derive(Debug)]
-pub struct Disk {}
+pub struct Disk {
+    file: File,
+    /// Set when a write fails, and reported by the next sync.
+    write_error: AtomicBool,
+    /// Superblock fields kept over ext4_rs's copy.
+    superblock: SuperblockFields,
+    /// The image's mtime after this process last wrote it. Held across
+    /// each write, so that any other change to the mtime is not ours.
+    own_mtime: Mutex<Option<SystemTime>>,
+    /// Set when a write is found to have come from outside just before
+    /// one of ours, whose mtime would hide it.
+    external_write: AtomicBool,
+    /// Set for a read-only mount, when no write may reach the image.
+    read_only: AtomicBool,
+}
+
+impl Disk {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mtime = file.metadata().and_then(|meta| meta.modified()).ok();
+        Ok(Self {
+            file,
+            write_error: AtomicBool::new(false),
+            superblock: SuperblockFields::default(),
+            own_mtime: Mutex::new(mtime),
+            external_write: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+        })
+    }
+
+    /// Drops every write from now on, whoever makes it, ext4_rs included.
+    pub fn set_read_only(&self) {
+        self.read_only.store(true, Ordering::Release);
+    }
+
+    /// Returns whether something other than this process wrote the image
+    /// since the last call. A write from outside within the same mtime
+    /// tick as one of ours goes unnoticed.
+    pub fn changed_externally(&self) -> bool {
+        let mut own_mtime = self.own_mtime.lock().unwrap();
+        let mtime = self.mtime();
+        let changed = self.external_write.swap(false, Ordering::AcqRel) || mtime != *own_mtime;
+        *own_mtime = mtime;
+        changed
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        self.file.metadata().and_then(|meta| meta.modified()).ok()
+    }
+
+    /// Sets a superblock field ext4_rs would otherwise overwrite with the
+    /// value it read at mount, and writes the superblock with it.
+    pub fn set_superblock_field(&self, offset: usize, value: &[u8]) {
+        self.superblock.set(offset, value);
+        self.write_superblock();
+    }
+
+    /// Writes the superblock with the fields set in `superblock`.
+    pub fn write_superblock(&self) {
+        let mut sb = self.read_offset(ondisk::SUPERBLOCK_OFFSET);
+        sb.truncate(superblock::SUPERBLOCK_SIZE);
+        self.write_offset(ondisk::SUPERBLOCK_OFFSET, &sb);
+    }
+
+    /// Whether a write failed since the last sync, which still has to
+    /// report it.
+    pub fn write_failed(&self) -> bool {
+        self.write_error.load(Ordering::Acquire)
+    }
+
+    /// Flushes the image to stable storage, like fsync(2) or fdatasync(2).
+    /// Fails if any write since the last sync was lost.
+    pub fn sync(&self, datasync: bool) -> io::Result<()> {
+        if datasync {
+            self.file.sync_data()?;
+        } else {
+            self.file.sync_all()?;
+        }
+        if self.write_error.swap(false, Ordering::AcqRel) {
+            return Err(io::Error::other("earlier write to image failed"));
+        }
+        Ok(())
+    }
+}
 
 impl BlockDevice for Disk {
     fn read_offset(&self, offset: usize) -> Vec<u8> {
         // log::debug!("disk read_offset: {:x} ({})", offset, offset);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("ex4.img")
-            .unwrap();
         let mut buf = vec![0u8; BLOCK_SIZE];
-        let _ = file.seek(std::io::SeekFrom::Start(offset as u64));
-        let _ = file.read_exact(&mut buf);
+        let _ = self.file.read_exact_at(&mut buf, offset as u64);
 
         buf
     }
 
     fn write_offset(&self, offset: usize, data: &[u8]) {
         // log::debug!("disk write_offset: {:x} ({}), data_len: {}", offset, offset, data.len());
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("ex4.img")
-            .unwrap();
-
-        let _ = file.seek(std::io::SeekFrom::Start(offset as u64));
-        let _ = file.write_all(data);
+        if self.read_only.load(Ordering::Acquire) {
+            log::debug!("dropped write at {:#x} on a read-only mount", offset);
+            return;
+        }
+        let data = self.superblock.patch(offset, data);
+        let mut own_mtime = self.own_mtime.lock().unwrap();
+        if self.mtime() != *own_mtime {
+            self.external_write.store(true, Ordering::Release);
+        }
+        if let Err(e) = self.file.write_all_at(&data, offset as u64) {
+            log::error!("disk write at {:#x} failed: {}", offset, e);
+            self.write_error.store(true, Ordering::Release);
+        }
+        *own_mtime = self.mtime();
     }
 }
 
 struct Ext4Fuse {
     ext4: Ext4,
+    disk: Arc<Disk>,
+    layout: Layout,
+    config: Config,
+    locks: Mutex<LockManager>,
+    dirs: Mutex<DirHandles>,
+    /// Held by operations on an inode, exclusively by those changing it.
+    inodes: InodeLocks,
+    /// Held by every operation that may allocate or free blocks or inodes,
+    /// as the bitmaps, group descriptors and superblock counters ext4_rs
+    /// updates are shared by all inodes. Taken after the inode locks.
+    alloc: Mutex<()>,
+    /// How many references to each inode the kernel holds.
+    inode_table: InodeTable,
+    /// `s_state` at mount, written back at unmount.
+    mount_state: OnceLock<u16>,
+    notify: Notify,
+    lazy_times: LazyTimes,
 }
 
 impl Ext4Fuse {
-    pub fn new(ext4: Ext4) -> Self {
-        Self { ext4 }
+    pub fn new(ext4: Ext4, disk: Arc<Disk>, config: Config) -> Self {
+        let layout = Layout::load(&disk);
+        Self {
+            ext4,
+            disk,
+            layout,
+            config,
+            locks: Mutex::default(),
+            dirs: Mutex::default(),
+            inodes: InodeLocks::default(),
+            alloc: Mutex::default(),
+            inode_table: InodeTable::default(),
+            mount_state: OnceLock::new(),
+            notify: Notify::default(),
+            lazy_times: LazyTimes::default(),
+        }
     }
-}
-
-impl Filesystem for Ext4Fuse {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        log::info!("lookup parent: {}, name: {:?}", parent, name);
-        // fuse use 1 as root inode
-        let parent = match parent {
-            // root
-            1 => 2,
-            _ => parent,
-        };
-
-        let r = self.ext4.fuse_lookup(parent, name.to_str().unwrap());
 
-        if r.is_err() {
-            log::warn!("lookup failed for name {:?} in parent {}: {:?}", name, parent, r.err());
-            reply.error(ENOENT);
-            return;
+    /// The extent map of the file at `path` in the image, for `--extents`.
+    fn extent_map(&self, path: &Path) -> Result<String, i32> {
+        let mut ino = 2;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => ino = self.lookup_name(ino, name)?.ino,
+                Component::RootDir | Component::CurDir => {}
+                _ => return Err(EINVAL),
+            }
         }
+        let tree = self.extents().load(ino as u32)?;
+        Ok(extent_map::describe(&tree))
+    }
 
-        let file_attr = r.unwrap();
-        log::info!("lookup successful: ino={}, size={}, kind={:?}", file_attr.ino, file_attr.size, file_attr.kind);
-
-        let file_kind = match file_attr.kind {
-            InodeFileType::S_IFREG => FileType::RegularFile,
-            InodeFileType::S_IFDIR => FileType::Directory,
-            _ => FileType::RegularFile,
-        };
-
-        let file_perm = file_attr.perm.bits();
-
-        let attr = FileAttr {
-            ino: file_attr.ino,
-            size: file_attr.size,
-            blocks: file_attr.blocks,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: file_kind,
-            perm: file_perm,
-            nlink: file_attr.nlink,
-            uid: file_attr.uid,
-            gid: file_attr.gid,
-            rdev: 0,
-            flags: 0,
-            blksize: BLOCK_SIZE as u32,
-        };
-
-        reply.entry(&TTL, &attr, 0);
+    fn xattrs(&self) -> XattrStore<'_> {
+        XattrStore {
+            ext4: &self.ext4,
+            disk: &self.disk,
+            layout: &self.layout,
+        }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        log::info!("getattr ino: {}, fh: {:?}", ino, _fh);
-        let inode = match ino {
-            // root
-            1 => 2,
-            _ => ino,
-        };
+    fn extents(&self) -> ExtentStore<'_> {
+        ExtentStore {
+            ext4: &self.ext4,
+            disk: &self.disk,
+            layout: &self.layout,
+        }
+    }
 
-        let r = self.ext4.fuse_getattr(inode);
+    fn xattr_get(&self, req: &Caller, ino: u64, name: &OsStr) -> Result<Vec<u8>, i32> {
+        let (index, suffix) = xattr::parse_name(name.as_bytes())?;
+        let attr = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?;
+        xattr::check_namespace(index, req.uid(), attr.kind, false)?;
+        if index == XATTR_INDEX_USER {
+            self.permit(req, ino, R_OK)?;
+        }
+        if matches!(index, XATTR_INDEX_POSIX_ACL_ACCESS | XATTR_INDEX_POSIX_ACL_DEFAULT) {
+            let acl = self.get_acl(ino, index)?.ok_or(ENODATA)?;
+            return Ok(acl.to_xattr());
+        }
+        self.xattrs()
+            .get(ino as u32, index, suffix)?
+            .ok_or(ENODATA)
+    }
 
-        if r.is_err() {
-            log::warn!("getattr failed for ino {}: {:?}", ino, r.err());
-            reply.error(ENOENT);
-            return;
+    fn xattr_set(
+        &self,
+        req: &Caller,
+        ino: u64,
+        name: &OsStr,
+        value: Option<&[u8]>,
+        flags: i32,
+    ) -> Result<(), i32> {
+        let (index, suffix) = xattr::parse_name(name.as_bytes())?;
+        let attr = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?;
+        xattr::check_namespace(index, req.uid(), attr.kind, true)?;
+        if index == XATTR_INDEX_USER {
+            self.permit(req, ino, W_OK)?;
+        }
+        if matches!(index, XATTR_INDEX_POSIX_ACL_ACCESS | XATTR_INDEX_POSIX_ACL_DEFAULT) {
+            return self.xattr_set_acl(req, ino, index, value);
         }
+        self.xattrs().set(ino as u32, index, suffix, value, flags)
+    }
 
-        let file_attr = r.unwrap();
-        log::info!("getattr successful: ino={}, size={}, kind={:?}", file_attr.ino, file_attr.size, file_attr.kind);
+    /// setfacl path: validates the ACL, keeps the mode bits in sync with it
+    /// and drops access ACLs that the mode bits alone can express.
+    fn xattr_set_acl(
+        &self,
+        req: &Caller,
+        ino: u64,
+        index: u8,
+        value: Option<&[u8]>,
+    ) -> Result<(), i32> {
+        let attr = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?;
+        if req.uid() != 0 && req.uid() != attr.uid {
+            return Err(EPERM);
+        }
 
-        let file_kind = match file_attr.kind {
-            InodeFileType::S_IFREG => FileType::RegularFile,
-            InodeFileType::S_IFDIR => FileType::Directory,
-            _ => FileType::RegularFile,
-        };
+        let acl = value
+            .filter(|value| !value.is_empty())
+            .map(Acl::from_xattr)
+            .transpose()?;
 
-        let file_perm = file_attr.perm.bits();
+        if index == XATTR_INDEX_POSIX_ACL_DEFAULT {
+            if attr.kind != InodeFileType::S_IFDIR {
+                return if acl.is_some() { Err(EACCES) } else { Ok(()) };
+            }
+            return self.set_acl(ino, index, acl.as_ref());
+        }
 
-        let attr = FileAttr {
-            ino: file_attr.ino,
-            size: file_attr.size,
-            blocks: file_attr.blocks,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: file_kind,
-            perm: file_perm,
-            nlink: file_attr.nlink,
-            uid: file_attr.uid,
-            gid: file_attr.gid,
-            rdev: 0,
-            flags: 0,
-            blksize: BLOCK_SIZE as u32,
+        let Some(acl) = acl else {
+            return self.set_acl(ino, index, None);
         };
-
-        reply.attr(&TTL, &attr);
+        let (mode, equiv) = acl.equiv_mode();
+        self.set_mode_bits(ino, mode);
+        self.set_acl(ino, index, (!equiv).then_some(&acl))
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        inode: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<TimeOrNow>,
-        mtime: Option<TimeOrNow>,
-        ctime: Option<SystemTime>,
-        fh: Option<u64>,
-        crtime: Option<SystemTime>,
-        chgtime: Option<SystemTime>,
-        bkuptime: Option<SystemTime>,
-        flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        log::info!("setattr ino: {}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}", 
-                   inode, mode, uid, gid, size);
-        let inode = match inode {
-            // root
-            1 => 2,
-            _ => inode,
-        };
-
-        let now = system_time_to_secs(SystemTime::now());
-
-        let mut atime_secs = None;
-        if let Some(atime) = atime {
-            let secs = match atime {
-                TimeOrNow::SpecificTime(t) => system_time_to_secs(t),
-                TimeOrNow::Now => now,
-            };
-            atime_secs = Some(secs);
-        }
-
-        let mut mtime_secs = None;
-        if let Some(mtime) = mtime {
-            let secs = match mtime {
-                TimeOrNow::SpecificTime(t) => system_time_to_secs(t),
-                TimeOrNow::Now => now,
-            };
-            mtime_secs = Some(secs);
+    /// Loads an inode's access or default ACL.
+    fn get_acl(&self, ino: u64, index: u8) -> Result<Option<Acl>, i32> {
+        match self.xattrs().get(ino as u32, index, b"")? {
+            Some(value) => Acl::from_disk(&value).map(Some).map_err(|_| EIO),
+            None => Ok(None),
         }
+    }
 
-        let mut ctime_secs = None;
-        if let Some(ctime) = ctime {
-            let secs = system_time_to_secs(ctime);
-            ctime_secs = Some(secs);
+    /// Stores or, with `None`, removes an inode's access or default ACL.
+    fn set_acl(&self, ino: u64, index: u8, acl: Option<&Acl>) -> Result<(), i32> {
+        let value = acl.map(Acl::to_disk);
+        match self.xattrs().set(ino as u32, index, b"", value.as_deref(), 0) {
+            Err(ENODATA) if acl.is_none() => Ok(()),
+            r => r,
         }
+    }
 
-        let mut crtime_secs = None;
-        if let Some(crtime) = crtime {
-            let secs = system_time_to_secs(crtime);
-            crtime_secs = Some(secs);
-        }
+    /// Replaces the permission bits of an inode, keeping its type and the
+    /// setuid, setgid and sticky bits.
+    fn set_mode_bits(&self, ino: u64, perm: u32) {
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        let mode = ondisk::le16(&raw, ondisk::I_MODE);
+        ondisk::put16(&mut raw, ondisk::I_MODE, (mode & !0o777) | (perm & 0o777) as u16);
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+    }
 
-        let mut chgtime_secs = None;
-        if let Some(chgtime) = chgtime {
-            let secs = system_time_to_secs(chgtime);
-            chgtime_secs = Some(secs);
+    /// Derives a new inode's mode from the parent's default ACL, which takes
+    /// the place of the umask. Returns the mode and umask to create with and
+    /// the default ACL to inherit.
+    fn create_mode(&self, parent: u64, mode: u32, umask: u32) -> (u32, u32, Option<Acl>) {
+        match self.get_acl(parent, XATTR_INDEX_POSIX_ACL_DEFAULT) {
+            Ok(Some(default_acl)) => {
+                let mut mode = mode;
+                default_acl.clone().create_masq(&mut mode);
+                (mode, 0, Some(default_acl))
+            }
+            _ => (mode, umask, None),
         }
+    }
 
-        let mut bkuptime_secs = None;
-        if let Some(bkuptime) = bkuptime {
-            let secs = system_time_to_secs(bkuptime);
-            bkuptime_secs = Some(secs);
+    /// Stores the ACLs a new inode inherits from its parent's default ACL.
+    fn inherit_acls(&self, ino: u64, default_acl: &Acl, mode: u32, is_dir: bool) -> Result<(), i32> {
+        let mut access_acl = default_acl.clone();
+        let mut mode = mode;
+        if access_acl.create_masq(&mut mode) {
+            self.set_acl(ino, XATTR_INDEX_POSIX_ACL_ACCESS, Some(&access_acl))?;
         }
-
-        self.ext4.fuse_setattr(
-            inode,
-            mode,
-            uid,
-            gid,
-            size,
-            atime_secs,
-            mtime_secs,
-            ctime_secs,
-            fh,
-            crtime_secs,
-            chgtime_secs,
-            bkuptime_secs,
-            flags,
-        );
-
-        let r = self.ext4.fuse_getattr(inode);
-        if r.is_err() {
-            log::error!("setattr: getattr failed after setattr for ino {}: {:?}", inode, r.err());
-            reply.error(EIO);
-            return;
+        if is_dir {
+            self.set_acl(ino, XATTR_INDEX_POSIX_ACL_DEFAULT, Some(default_acl))?;
         }
-        let file_attr = r.unwrap();
-
-        let file_kind = match file_attr.kind {
-            InodeFileType::S_IFREG => FileType::RegularFile,
-            InodeFileType::S_IFDIR => FileType::Directory,
-            _ => FileType::RegularFile,
-        };
-
-        let file_perm = file_attr.perm.bits();
-
-        let response_attr = FileAttr {
-            ino: file_attr.ino,
-            size: file_attr.size,
-            blocks: file_attr.blocks,
-            atime: timestamp_to_system_time(file_attr.atime),
-            mtime: timestamp_to_system_time(file_attr.mtime),
-            ctime: timestamp_to_system_time(file_attr.ctime),
-            crtime: timestamp_to_system_time(file_attr.crtime),
-            kind: file_kind,
-            perm: file_perm,
-            nlink: file_attr.nlink,
-            uid: file_attr.uid,
-            gid: file_attr.gid,
-            rdev: 0,
-            flags: 0,
-            blksize: BLOCK_SIZE as u32,
-        };
+        Ok(())
+    }
 
-        log::info!("setattr successful for ino {}", inode);
-        reply.attr(&Duration::from_secs(1), &response_attr); // 缓存时间可调整
+    /// Makes everything written so far durable. ext4_rs updates metadata
+    /// and data in place without a journal, so there is no transaction to
+    /// commit and syncing the image file covers every inode.
+    fn sync(&self, datasync: bool) -> Result<(), i32> {
+        self.disk.sync(datasync).map_err(|e| {
+            log::error!("sync of {} failed: {}", IMAGE_PATH, e);
+            EIO
+        })
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        flags: i32,
-        lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        log::info!("read ino: {}, fh: {}, offset: {}, size: {}, flags: {}", 
-                   ino, fh, offset, size, flags);
-        let inode = match ino {
-            // root
-            1 => 2,
-            _ => ino,
-        };
-        let r = self.ext4.fuse_read(inode, fh, offset, size, flags, lock);
-        match r {
-            Ok(data) => {
-                log::info!("read successful: {} bytes returned", data.len());
-                reply.data(&data)
-            },
-            Err(e) => {
-                log::warn!("read failed for ino {}: {:?}", ino, e);
-                reply.error(ENOENT)
-            },
+    /// Reports a write to the image that failed since the last sync. The
+    /// error stays for the next fsync(2) to report as well.
+    fn deferred_error(&self) -> Result<(), i32> {
+        if self.disk.write_failed() {
+            log::warn!("an earlier write to {} failed", IMAGE_PATH);
+            return Err(EIO);
         }
+        Ok(())
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        log::info!("readdir ino: {}, fh: {}, offset: {}", ino, fh, offset);
-        let inode = match ino {
-            // root
-            1 => 2,
-            _ => ino,
-        };
-
-        let r = self.ext4.fuse_readdir(inode, fh, offset);
-        match r {
-            Ok(entries) => {
-                log::info!("readdir found {} entries", entries.len());
-                for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
-                    let name = entry.get_name();
-                    let detype = entry.get_de_type();
-                    let kind = match detype {
-                        1 => FileType::RegularFile,
-                        2 => FileType::Directory,
-                        _ => FileType::RegularFile,
-                    };
-                    log::debug!("readdir entry: name={}, inode={}, type={}", name, entry.inode, detype);
-                    let _ = reply.add(entry.inode as u64, (i + 1) as i64, kind, &name);
-                }
-                reply.ok();
+    /// Polls the image for writes from outside this process and has the
+    /// kernel drop what it cached of every inode it references and every
+    /// name it was given, found or not. Also writes back the atimes
+    /// lazytime has held for too long.
+    fn watch_image(&self) {
+        // Whatever happened before the mount is already on the image.
+        self.disk.changed_externally();
+        loop {
+            std::thread::sleep(IMAGE_POLL_INTERVAL);
+            self.flush_old_times(LAZYTIME_MAX_AGE);
+            self.notify.expire_entries();
+            if !self.disk.changed_externally() {
+                continue;
+            }
+            let inodes = self.inode_table.inodes();
+            log::info!("{} changed outside the mount, invalidating {} inodes", IMAGE_PATH, inodes.len());
+            self.notify.entries();
+            // The kernel knows the root as 1.
+            self.notify.inode(1);
+            for ino in inodes {
+                self.notify.inode(ino);
             }
-            Err(e) => {
-                log::warn!("readdir failed for ino {}: {:?}", ino, e);
-                reply.error(ENOENT)
-            },
         }
     }
 
-    fn write(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        write_flags: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        log::info!("write ino: {}, fh: {}, offset: {}, data_len: {}, write_flags: {}, flags: {}", 
-                   ino, fh, offset, data.len(), write_flags, flags);
-        let inode = match ino {
-            // root
-            1 => 2,
-            _ => ino,
-        };
-
-        let r = self
-            .ext4
-            .fuse_write(inode, fh, offset, data, write_flags, flags, lock_owner);
-        match r {
-            Ok(size) => {
-                log::info!("write successful: {} bytes written", size);
-                reply.written(size as u32)
-            },
-            Err(e) => {
-                log::warn!("write failed for ino {}: {:?}", ino, e);
-                reply.error(ENOENT)
-            },
-        }
+    fn raw_mode(&self, ino: u64) -> u32 {
+        let raw = self.layout.read_inode(&self.disk, ino as u32);
+        ondisk::le16(&raw, ondisk::I_MODE) as u32
     }
 
-    /// Remove a file.
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        log::info!("unlink parent: {}, name: {:?}", parent, name);
-        let parent = match parent {
-            // root
-            1 => 2,
-            _ => parent,
+    /// ext4_rs creates every node as a regular file with an extent tree.
+    /// Device, FIFO and socket inodes instead keep their device number, if
+    /// any, in `i_block`, and their type in the parent's entry.
+    fn make_special(&self, parent: u64, name: &OsStr, ino: u32, mode: u32, rdev: u32) -> Result<(), i32> {
+        let mut raw = self.layout.read_inode(&self.disk, ino);
+        let perm = ondisk::le16(&raw, ondisk::I_MODE) as u32 & !S_IFMT;
+        ondisk::put16(&mut raw, ondisk::I_MODE, ((mode & S_IFMT) | perm) as u16);
+        let flags = iflags::get(&raw) & !EXT4_EXTENTS_FL;
+        iflags::set(&mut raw, flags);
+        let rdev = match mode & S_IFMT {
+            S_IFCHR | S_IFBLK => rdev,
+            _ => 0,
         };
+        ondisk::set_inode_rdev(&mut raw, rdev);
+        self.layout.write_inode(&self.disk, ino, &mut raw);
 
-        let r = self.ext4.fuse_unlink(parent, name.to_str().unwrap());
-        match r {
-            Ok(_) => {
-                log::info!("unlink successful for {:?}", name);
-                reply.ok()
-            },
-            Err(e) => {
-                log::warn!("unlink failed for {:?}: {:?}", name, e);
-                reply.error(ENOENT)
-            },
+        let ft = dirent::file_type(mode);
+        dirent::set_file_type(&self.extents(), parent as u32, name.as_bytes(), ino, ft)
+    }
+
+    /// The group and mode of a new inode in `parent`, as the kernel's
+    /// `inode_init_owner`: a setgid directory passes on its group, and to
+    /// subdirectories its setgid bit too. A file created there keeps
+    /// setgid on an executable only if the caller is in that group.
+    fn create_owner(&self, req: &Caller, parent: u64, mode: u32) -> (u32, u32) {
+        if self.raw_mode(parent) & S_ISGID == 0 {
+            return (req.gid(), mode);
+        }
+        let gid = self.ext4.fuse_getattr(parent).map_or(req.gid(), |dir| dir.gid);
+        if mode & S_IFMT == S_IFDIR {
+            return (gid, mode | S_ISGID);
         }
+        if mode & (S_ISGID | S_IXGRP) == S_ISGID | S_IXGRP {
+            let cred = Credentials::from_request(req);
+            if !cred.is_root() && !cred.in_group(gid) {
+                return (gid, mode & !S_ISGID);
+            }
+        }
+        (gid, mode)
     }
 
-    /// Create file node.
-    /// Create a regular file, character device, block device, fifo or socket node.
-    fn mknod(
-        &mut self,
-        _req: &Request<'_>,
+    /// Sets all permission bits of a new inode, setuid, setgid and sticky
+    /// included.
+    fn init_perm(&self, ino: u64, perm: u32) {
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        let mode = ondisk::le16(&raw, ondisk::I_MODE) as u32;
+        ondisk::put16(&mut raw, ondisk::I_MODE, ((mode & S_IFMT) | (perm & 0o7777)) as u16);
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+    }
+
+    /// Creates a file, device, FIFO or socket node and sets up what
+    /// ext4_rs leaves out. Returns the attributes of the created inode.
+    fn make_node(
+        &self,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
         mode: u32,
         umask: u32,
         rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        log::info!("mknod parent: {}, name: {:?}, mode: {:o}, umask: {:o}, rdev: {}", 
-                   parent, name, mode, umask, rdev);
-        let parent = match parent {
-            // root
-            1 => 2,
-            _ => parent,
-        };
-
-        let r = self.ext4.fuse_mknod_with_attr(
-            parent,
-            name.to_str().unwrap(),
-            mode,
-            umask,
-            rdev,
-            _req.uid(),
-            _req.gid(),
-        );
-
-        match r {
-            Ok(inode_ref) => {
-                let inode_num = inode_ref.inode_num;
-                log::info!("mknod successful: created inode {}", inode_num);
-                let attr = FileAttr {
-                    ino: inode_num as u64,
-                    size: 0,
-                    blocks: 0,
-                    atime: UNIX_EPOCH,
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    crtime: UNIX_EPOCH,
-                    kind: FileType::RegularFile,
-                    perm: inode_ref.inode.file_perm().bits(),
-                    nlink: 1,
-                    uid: _req.uid(),
-                    gid: _req.gid(),
-                    rdev,
-                    flags: 0,
-                    blksize: BLOCK_SIZE as u32,
+    ) -> Result<FileAttr, i32> {
+        let (mode, umask, default_acl) = self.create_mode(parent, mode, umask);
+        let (gid, mode) = self.create_owner(req, parent, mode);
+        let ino = match name.to_str() {
+            Some(utf8) => {
+                self.ext4
+                    .fuse_mknod_with_attr(parent, utf8, mode, umask, rdev, req.uid(), gid)
+                    .map_err(errno::from_ext4)?
+                    .inode_num
+            }
+            None => {
+                let mode = match mode & S_IFMT {
+                    0 => S_IFREG | mode,
+                    _ => mode,
                 };
+                self.create_inode(parent, name.as_bytes(), mode, req.uid(), gid)?
+            }
+        };
 
-                reply.entry(&TTL, &attr, 0);
+        self.init_perm(ino as u64, mode & !umask);
+        if is_special(mode) {
+            if let Err(e) = self.make_special(parent, name, ino, mode, rdev) {
+                log::warn!("mknod: failed to make inode {} a special file: {}", ino, e);
+                self.discard_new(parent, name, ino);
+                return Err(e);
             }
-            Err(e) => {
-                log::warn!("mknod failed for {:?}: {:?}", name, e);
-                reply.error(ENOENT);
+        }
+        if let Some(acl) = &default_acl {
+            if let Err(e) = self.inherit_acls(ino as u64, acl, mode, false) {
+                log::warn!("mknod: failed to inherit ACL for inode {}: {}", ino, e);
             }
         }
+        self.touch(ino as u64, &NEW_INODE_TIMES);
+        self.touch(parent, &[InodeTime::Modify, InodeTime::Change]);
+        self.entry_attr(ino as u64)
     }
 
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
+    /// Creates a directory, counting its ".." as a link to `parent`.
+    /// Returns the attributes of the created inode.
+    fn make_dir(
+        &self,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
         mode: u32,
         umask: u32,
-        reply: ReplyEntry,
-    ) {
-        log::info!("mkdir parent: {}, name: {:?}, mode: {:o}, umask: {:o}", parent, name, mode, umask);
-        let parent = match parent {
-            // root
-            1 => 2,
-            _ => parent,
+    ) -> Result<FileAttr, i32> {
+        let (mode, umask, default_acl) = self.create_mode(parent, mode, umask);
+        let (gid, mode) = self.create_owner(req, parent, S_IFDIR | mode);
+        let mode = mode & !S_IFMT;
+        let parent_links = self.links_count(parent as u32);
+        let ino = match name.to_str() {
+            Some(utf8) => {
+                self.ext4
+                    .fuse_mkdir_with_attr(parent, utf8, mode, umask, req.uid(), gid)
+                    .map_err(errno::from_ext4)?
+                    .inode_num
+            }
+            None => self.create_inode(parent, name.as_bytes(), S_IFDIR | mode, req.uid(), gid)?,
         };
 
-        let inode_ref = self
-            .ext4
-            .fuse_mkdir_with_attr(
-                parent,
-                name.to_str().unwrap(),
-                mode,
-                umask,
-                _req.uid(),
-                _req.gid(),
-            )
-            .unwrap();
-
-        let inode_num = inode_ref.inode_num;
-        log::info!("mkdir successful: created directory inode {}", inode_num);
-        let attr = FileAttr {
-            ino: inode_num as u64,
-            size: 0,
-            blocks: 0,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: FileType::Directory,
-            perm: 0o777,
-            nlink: 2,
-            uid: _req.uid(),
-            gid: _req.gid(),
-            rdev: 0,
-            flags: 0,
-            blksize: BLOCK_SIZE as u32,
-        };
+        self.init_perm(ino as u64, mode & !umask);
+        if self.links_count(ino) < 2 {
+            self.set_links_count(ino, 2);
+        }
+        if self.links_count(parent as u32) == parent_links {
+            self.add_dir_link(parent as u32, true);
+        }
+        if let Some(acl) = &default_acl {
+            if let Err(e) = self.inherit_acls(ino as u64, acl, mode, true) {
+                log::warn!("mkdir: failed to inherit ACL for inode {}: {}", ino, e);
+            }
+        }
+        self.touch(ino as u64, &NEW_INODE_TIMES);
+        self.touch(parent, &[InodeTime::Modify, InodeTime::Change]);
+        self.entry_attr(ino as u64)
+    }
+
+    /// The attributes of `ino` as they are on disk.
+    fn entry_attr(&self, ino: u64) -> Result<FileAttr, i32> {
+        let attr = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?;
+        Ok(self.file_attr(&attr))
+    }
+
+    /// Creates an inode of `mode` with the entry `name` in `parent`, for
+    /// names ext4_rs cannot take. Nothing is left allocated if it fails.
+    fn create_inode(
+        &self,
+        parent: u64,
+        name: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<u32, i32> {
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = self.ext4.ialloc_alloc_inode(is_dir).map_err(errno::from_ext4)?;
+        // A new generation, so handles to the previous user go stale.
+        let old = self.layout.read_inode(&self.disk, ino);
+        let generation = ondisk::le32(&old, ondisk::I_GENERATION).wrapping_add(1);
+        let mut raw = vec![0u8; self.layout.inode_size];
+        ondisk::init_inode(&mut raw, mode, uid, gid, if is_dir { 2 } else { 1 }, generation);
+        extent::reset_root(&mut raw);
+        self.layout.write_inode(&self.disk, ino, &mut raw);
+
+        let store = self.extents();
+        let r = match is_dir {
+            true => dirent::init_dir(&store, ino, parent as u32),
+            false => Ok(()),
+        }
+        .and_then(|_| dirent::add_entry(&store, parent as u32, name, ino, dirent::file_type(mode)));
+        if let Err(e) = r {
+            self.set_links_count(ino, 0);
+            if let Err(e) = self.free_inode(ino) {
+                log::error!("failed to free inode {} of a failed create: {}", ino, e);
+            }
+            return Err(e);
+        }
+        Ok(ino)
+    }
+
+    /// Removes the entry `name` from `parent` and the link it holds, for
+    /// names ext4_rs cannot take.
+    fn remove_link(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        let store = self.extents();
+        let ino = dirent::lookup(&store, parent as u32, name.as_bytes())?;
+        dirent::remove_entry(&store, parent as u32, name.as_bytes(), ino)?;
+        self.drop_link(ino)
+    }
+
+    /// Takes a link from `ino` after its entry went, and frees it with the
+    /// last one. A directory only ever has the one entry.
+    fn drop_link(&self, ino: u32) -> Result<(), i32> {
+        let links = match self.raw_mode(ino as u64) & S_IFMT {
+            S_IFDIR => 0,
+            _ => self.links_count(ino).saturating_sub(1),
+        };
+        self.set_links_count(ino, links);
+        match links {
+            0 => self.free_inode(ino),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renames `name` in `parent` to `newname` in `newparent` entry by
+    /// entry, for names ext4_rs cannot take. `check_rename` has already
+    /// passed.
+    fn rename_entry(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), i32> {
+        let store = self.extents();
+        let (dir, newdir) = (parent as u32, newparent as u32);
+        let (name, newname) = (name.as_bytes(), newname.as_bytes());
+        let ino = dirent::lookup(&store, dir, name)?;
+        let ft = dirent::file_type(self.raw_mode(ino as u64));
+        let moved_dir = ft == dirent::EXT4_FT_DIR && dir != newdir;
+
+        match dirent::lookup(&store, newdir, newname) {
+            Ok(target) if flags & RENAME_EXCHANGE != 0 => {
+                let target_ft = dirent::file_type(self.raw_mode(target as u64));
+                dirent::set_inode(&store, dir, name, ino, target, target_ft)?;
+                dirent::set_inode(&store, newdir, newname, target, ino, ft)?;
+                if moved_dir {
+                    self.reparent(ino, dir, newdir)?;
+                }
+                if target_ft == dirent::EXT4_FT_DIR && dir != newdir {
+                    self.reparent(target, newdir, dir)?;
+                }
+            }
+            Ok(target) => {
+                let target_is_dir = self.raw_mode(target as u64) & S_IFMT == S_IFDIR;
+                dirent::set_inode(&store, newdir, newname, target, ino, ft)?;
+                dirent::remove_entry(&store, dir, name, ino)?;
+                if moved_dir {
+                    self.reparent(ino, dir, newdir)?;
+                }
+                if target_is_dir {
+                    self.add_dir_link(newdir, false);
+                }
+                self.drop_link(target)?;
+            }
+            Err(ENOENT) if flags & RENAME_EXCHANGE == 0 => {
+                dirent::add_entry(&store, newdir, newname, ino, ft)?;
+                dirent::remove_entry(&store, dir, name, ino)?;
+                if moved_dir {
+                    self.reparent(ino, dir, newdir)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Points ".." of the directory `ino` moved from `from` to `to`, and
+    /// moves the link it counts along.
+    fn reparent(&self, ino: u32, from: u32, to: u32) -> Result<(), i32> {
+        dirent::set_inode(&self.extents(), ino, b"..", from, to, dirent::EXT4_FT_DIR)?;
+        self.add_dir_link(from, false);
+        self.add_dir_link(to, true);
+        Ok(())
+    }
+
+    /// Counts one subdirectory more or less in the links of `dir`. A count
+    /// of 1 means too many subdirectories to count (dir_nlink) and stays.
+    fn add_dir_link(&self, dir: u32, more: bool) {
+        let links = match (self.links_count(dir), more) {
+            (0..=1, _) => return,
+            (links, true) => match links.saturating_add(1) {
+                EXT4_LINK_MAX.. => 1,
+                links => links,
+            },
+            (links, false) => (links - 1).max(2),
+        };
+        self.set_links_count(dir, links);
+    }
+
+    /// Removes the entry `name` in `parent` of the inode `ino` just created
+    /// there, and frees the inode.
+    fn discard_new(&self, parent: u64, name: &OsStr, ino: u32) {
+        let store = self.extents();
+        let r = dirent::remove_entry(&store, parent as u32, name.as_bytes(), ino).and_then(|_| {
+            self.set_links_count(ino, 0);
+            self.free_inode(ino)
+        });
+        if let Err(e) = r {
+            log::error!("failed to remove the new inode {} as {:?}: {}", ino, name, e);
+        }
+    }
+
+    /// Gives the last link of a special file an empty extent tree again, as
+    /// ext4_rs frees the blocks of a removed inode through its extent tree.
+    /// Returns the inode as it was, for `undo_removal` if the removal fails.
+    fn prepare_removal(&self, parent: u64, name: &OsStr) -> Option<(u32, Vec<u8>)> {
+        let attr = self.lookup_name(parent, name).ok()?;
+        if attr.nlink > 1 || !is_special(self.raw_mode(attr.ino)) {
+            return None;
+        }
+        let ino = attr.ino as u32;
+        let before = self.layout.read_inode(&self.disk, ino);
+        let mut raw = before.clone();
+        extent::reset_root(&mut raw);
+        self.layout.write_inode(&self.disk, ino, &mut raw);
+        Some((ino, before))
+    }
+
+    /// Gives a special file whose removal failed its device number back.
+    fn undo_removal(&self, prepared: Option<(u32, Vec<u8>)>) {
+        let Some((ino, before)) = prepared else {
+            return;
+        };
+        let block = ondisk::I_BLOCK..ondisk::I_BLOCK + ondisk::I_BLOCK_SIZE;
+        let mut raw = self.layout.read_inode(&self.disk, ino);
+        raw[block.clone()].copy_from_slice(&before[block]);
+        iflags::set(&mut raw, iflags::get(&before));
+        self.layout.write_inode(&self.disk, ino, &mut raw);
+    }
+
+    fn links_count(&self, ino: u32) -> u16 {
+        let raw = self.layout.read_inode(&self.disk, ino);
+        ondisk::le16(&raw, ondisk::I_LINKS_COUNT)
+    }
+
+    fn set_links_count(&self, ino: u32, links: u16) {
+        let mut raw = self.layout.read_inode(&self.disk, ino);
+        ondisk::put16(&mut raw, ondisk::I_LINKS_COUNT, links);
+        self.layout.write_inode(&self.disk, ino, &mut raw);
+    }
+
+    /// Keeps the non-directory `name` in `parent` allocated if its last
+    /// link is about to go while the kernel still references it, by giving
+    /// it an extra link for ext4_rs's removal to take. Returns the held
+    /// inode, for `orphan` once the entry is gone or `unhold` if it stays.
+    fn hold_unlinked(&self, parent: u64, name: &OsStr) -> Option<u32> {
+        let attr = self.lookup_name(parent, name).ok()?;
+        if attr.nlink != 1
+            || self.raw_mode(attr.ino) & S_IFMT == S_IFDIR
+            || !self.inode_table.is_referenced(attr.ino)
+        {
+            return None;
+        }
+        self.set_links_count(attr.ino as u32, 2);
+        Some(attr.ino as u32)
+    }
+
+    fn unhold(&self, held: Option<u32>) {
+        if let Some(ino) = held {
+            self.set_links_count(ino, 1);
+        }
+    }
+
+    /// Drops the link `hold_unlinked` added and puts the inode on the
+    /// orphan list, to be freed when the kernel forgets it.
+    fn orphan(&self, ino: u32) {
+        self.set_links_count(ino, 0);
+        self.touch(ino as u64, &[InodeTime::Change]);
+        orphan::add(&self.disk, &self.layout, ino);
+        log::info!("inode {} unlinked while in use, kept on the orphan list", ino);
+    }
+
+    /// Takes `ino` off the orphan list and frees it if it has no links
+    /// left, or else frees its blocks past EOF, as for an interrupted
+    /// truncate.
+    fn release_orphan(&self, ino: u32) -> Result<(), i32> {
+        orphan::remove(&self.disk, &self.layout, ino)?;
+        let raw = self.layout.read_inode(&self.disk, ino);
+        if ondisk::le16(&raw, ondisk::I_LINKS_COUNT) == 0 {
+            self.free_inode(ino)
+        } else {
+            self.truncate_blocks(ino, ondisk::inode_size(&raw))
+        }
+    }
+
+    /// Frees what a previous mount left on the orphan list.
+    fn release_orphans(&self) {
+        let _alloc = self.alloc.lock().unwrap();
+        let orphans = orphan::list(&self.disk, &self.layout);
+        if orphans.is_empty() {
+            return;
+        }
+        log::info!("processing {} orphan inodes", orphans.len());
+        for ino in orphans {
+            match self.release_orphan(ino) {
+                Ok(()) => log::info!("released orphan inode {}", ino),
+                Err(e) => log::warn!("failed to release orphan inode {}: {}", ino, e),
+            }
+        }
+        // Whatever could not be released is not followed again.
+        self.disk.set_superblock_field(superblock::S_LAST_ORPHAN, &0u32.to_le_bytes());
+    }
+
+    /// Frees an inode with no links left, with its blocks and xattr block.
+    fn free_inode(&self, ino: u32) -> Result<(), i32> {
+        let mode = self.raw_mode(ino as u64);
+        if is_special(mode) {
+            let mut raw = self.layout.read_inode(&self.disk, ino);
+            extent::reset_root(&mut raw);
+            self.layout.write_inode(&self.disk, ino, &mut raw);
+        }
+        self.truncate_blocks(ino, 0)?;
+        self.xattrs().clear(ino)?;
+        self.ext4.ialloc_free_inode(ino, mode & S_IFMT == S_IFDIR);
+        self.lazy_times.take(ino as u64);
+
+        let mut raw = self.layout.read_inode(&self.disk, ino);
+        ondisk::put32(&mut raw, ondisk::I_DTIME, system_time_to_secs(SystemTime::now()));
+        self.layout.write_inode(&self.disk, ino, &mut raw);
+        Ok(())
+    }
+
+    /// Frees the blocks of `ino` past `size`. Inodes without an extent
+    /// tree have none ext4_rs could have allocated.
+    fn truncate_blocks(&self, ino: u32, size: u64) -> Result<(), i32> {
+        if self.inode_flags(ino as u64) & EXT4_EXTENTS_FL == 0 {
+            return Ok(());
+        }
+        let store = self.extents();
+        let mut tree = store.load(ino)?;
+        let first = size.div_ceil(BLOCK_SIZE as u64) as u32;
+        let freed = tree.remove(first, u32::MAX);
+        store.store(ino, &mut tree, &freed)
+    }
+
+    /// Converts ext4_rs attributes, taking the file type and device number
+    /// from the raw inode since ext4_rs only knows files and directories,
+    /// and the timestamps since ext4_rs drops their nanoseconds and epoch
+    /// bits.
+    fn file_attr(&self, attr: &ext4_rs::FileAttr) -> FileAttr {
+        let raw = self.layout.read_inode(&self.disk, attr.ino as u32);
+        let mode = ondisk::le16(&raw, ondisk::I_MODE) as u32;
+        let rdev = match mode & S_IFMT {
+            S_IFCHR | S_IFBLK => ondisk::inode_rdev(&raw),
+            _ => 0,
+        };
+        let time = |which| {
+            self.lazy_times
+                .get(attr.ino, which)
+                .unwrap_or_else(|| ondisk::inode_time(&raw, which))
+        };
+
+        FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: time(InodeTime::Access),
+            mtime: time(InodeTime::Modify),
+            ctime: time(InodeTime::Change),
+            crtime: ondisk::inode_time(&raw, InodeTime::Create),
+            kind: file_type(mode),
+            perm: attr.perm.bits(),
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev,
+            flags: 0,
+            blksize: BLOCK_SIZE as u32,
+        }
+    }
+
+    /// Lists a directory from its raw entries, so names need not be UTF-8.
+    fn list_dir(&self, ino: u64) -> Result<Vec<DirEntry>, i32> {
+        let entries = dirent::read_dir(&self.extents(), ino as u32).map_err(|e| {
+            log::warn!("listing directory {} failed: {}", ino, e);
+            e
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                ino: entry.ino as u64,
+                kind: dir_entry_kind(entry.file_type),
+                name: OsString::from_vec(entry.name),
+            })
+            .collect())
+    }
+
+    /// Looks `name` up through ext4_rs, or by its bytes if it is not UTF-8.
+    fn lookup_name(&self, parent: u64, name: &OsStr) -> Result<ext4_rs::FileAttr, i32> {
+        if name.len() > dirent::EXT4_NAME_LEN {
+            return Err(ENAMETOOLONG);
+        }
+        if let Some(name) = name.to_str() {
+            return self.ext4.fuse_lookup(parent, name).map_err(errno::from_ext4);
+        }
+        let ino = dirent::lookup(&self.extents(), parent as u32, name.as_bytes())?;
+        self.ext4.fuse_getattr(ino as u64).map_err(errno::from_ext4)
+    }
+
+    /// Locks the directories in `entries` and the inodes their names refer
+    /// to for a change of all of them, retrying if an entry changes before
+    /// the locks are taken. The first lookups hold the directories shared,
+    /// so that no directory block is read while it is being rewritten.
+    fn lock_entries(&self, entries: &[(u64, &OsStr)]) -> InodeGuard<'_> {
+        let dirs: Vec<u64> = entries.iter().map(|&(dir, _)| dir).collect();
+        let children = || -> Vec<Option<u64>> {
+            entries
+                .iter()
+                .map(|&(dir, name)| self.lookup_name(dir, name).ok().map(|attr| attr.ino))
+                .collect()
+        };
+        loop {
+            let probe = self.inodes.lock(&dirs, &[]);
+            let expected = children();
+            drop(probe);
+            let mut inos = dirs.clone();
+            inos.extend(expected.iter().flatten());
+            let guard = self.inodes.exclusive(&inos);
+            if children() == expected {
+                return guard;
+            }
+        }
+    }
+
+    /// Entries of the directory handle `fh`, listed afresh when reading
+    /// from the start.
+    fn dir_snapshot(&self, ino: u64, fh: u64, offset: i64) -> Result<Arc<[DirEntry]>, i32> {
+        let snapshot = self.dirs.lock().unwrap().get(fh);
+        match snapshot {
+            Some(entries) if offset != 0 => Ok(entries),
+            _ => {
+                let before = self.layout.read_inode(&self.disk, ino as u32);
+                let entries = self.list_dir(ino)?;
+                self.note_access(ino, &before);
+                Ok(self.dirs.lock().unwrap().fill(fh, entries))
+            }
+        }
+    }
+
+    /// Updates atime after a read as the mount options and the inode's
+    /// noatime flag say. `before` is the inode as it was before the read,
+    /// which ext4_rs may have stamped itself. A read-only mount keeps
+    /// every atime as it is.
+    fn note_access(&self, ino: u64, before: &[u8]) {
+        if self.config.read_only {
+            return;
+        }
+        let now = SystemTime::now();
+        let time = |which| {
+            self.lazy_times
+                .get(ino, which)
+                .unwrap_or_else(|| ondisk::inode_time(before, which))
+        };
+        let (atime, mtime, ctime) = (time(InodeTime::Access), time(InodeTime::Modify), time(InodeTime::Change));
+        let update = iflags::get(before) & EXT4_NOATIME_FL == 0
+            && self.config.atime.wants_update(atime, mtime, ctime, now);
+        if update && !self.config.lazytime {
+            self.touch(ino, &[InodeTime::Access]);
+            return;
+        }
+        self.restore_atime(ino, before);
+        if update {
+            self.lazy_times.set(ino, &[InodeTime::Access], now);
+        }
+    }
+
+    /// Sets mtime and ctime after the contents of `ino` changed. lazytime
+    /// holds them back, saving a write of the inode for each write call.
+    fn touch_data(&self, ino: u64) {
+        let which = [InodeTime::Modify, InodeTime::Change];
+        if self.config.lazytime {
+            self.lazy_times.set(ino, &which, SystemTime::now());
+        } else {
+            self.touch(ino, &which);
+        }
+    }
+
+    /// Writes timestamps lazytime held back.
+    fn flush_times(&self, ino: u64, times: &[(InodeTime, SystemTime)]) {
+        if times.is_empty() {
+            return;
+        }
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        for &(which, time) in times {
+            ondisk::set_inode_time(&mut raw, which, time);
+        }
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+    }
+
+    /// Writes back the timestamps lazytime has held for `max_age` or longer.
+    fn flush_old_times(&self, max_age: Duration) {
+        for (ino, times) in self.lazy_times.take_older(max_age) {
+            let _guard = self.inodes.exclusive(&[ino]);
+            self.flush_times(ino, &times);
+        }
+    }
+
+    /// fsync(2) writes lazy timestamps back; fdatasync(2) leaves them, as
+    /// they are not needed to read the data.
+    fn sync_times(&self, ino: u64, datasync: bool) {
+        if datasync {
+            return;
+        }
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.exclusive(&[inode]);
+        self.flush_times(inode, &self.lazy_times.take(inode));
+    }
+
+    /// Undoes an atime update ext4_rs made.
+    fn restore_atime(&self, ino: u64, before: &[u8]) {
+        if self.config.read_only {
+            return;
+        }
+        let atime = ondisk::inode_time(before, InodeTime::Access);
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        if ondisk::inode_time(&raw, InodeTime::Access) != atime {
+            ondisk::set_inode_time(&mut raw, InodeTime::Access, atime);
+            self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+        }
+    }
+
+    /// Sets the `which` timestamps of `ino` to the current time, which is
+    /// returned.
+    fn touch(&self, ino: u64, which: &[InodeTime]) -> SystemTime {
+        let now = SystemTime::now();
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        for &time in which {
+            ondisk::set_inode_time(&mut raw, time, now);
+        }
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+        self.lazy_times.forget(ino, which);
+        now
+    }
+
+    fn inode_flags(&self, ino: u64) -> u32 {
+        iflags::get(&self.layout.read_inode(&self.disk, ino as u32))
+    }
+
+    /// FS_IOC_SETFLAGS and chflags(2). Only the owner or root may change
+    /// the flags of an inode.
+    fn set_inode_flags(&self, req: &Caller, ino: u64, flags: u32) -> Result<(), i32> {
+        let cred = Credentials::from_request(req);
+        let owner = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?.uid;
+        if !cred.is_root() && cred.uid != owner {
+            return Err(EPERM);
+        }
+
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        let flags = iflags::apply(iflags::get(&raw), flags, cred.is_root())?;
+        iflags::set(&mut raw, flags);
+        ondisk::set_inode_time(&mut raw, InodeTime::Change, SystemTime::now());
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+        Ok(())
+    }
+
+    /// Nothing may be changed on a read-only mount.
+    fn check_read_write(&self) -> Result<(), i32> {
+        if self.config.read_only {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    /// chown(2) and chgrp(2): only root may give a file away, and the
+    /// owner may only change its group to one they belong to.
+    fn may_chown(&self, cred: &Credentials, attr: &ext4_rs::FileAttr, uid: Option<u32>, gid: Option<u32>) -> Result<(), i32> {
+        if cred.is_root() {
+            return Ok(());
+        }
+        if uid.is_some_and(|uid| uid != attr.uid) {
+            return Err(EPERM);
+        }
+        if gid.is_some_and(|gid| gid != attr.gid && (cred.uid != attr.uid || !cred.in_group(gid))) {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// chmod(2) is for the owner and root. Setting setgid needs membership
+    /// of the file's group, or the bit is dropped. Anyone may clear only
+    /// setuid and setgid: FUSE_HANDLE_KILLPRIV is not negotiated, so the
+    /// kernel does that itself, with the writer's credentials, before a
+    /// write or truncate.
+    fn may_chmod(&self, cred: &Credentials, attr: &ext4_rs::FileAttr, gid: Option<u32>, mode: u32) -> Result<u32, i32> {
+        if cred.is_root() {
+            return Ok(mode);
+        }
+        if cred.uid != attr.uid {
+            let (old, new) = (self.raw_mode(attr.ino) & 0o7777, mode & 0o7777);
+            let kills_privs = new != old && new & !old == 0 && (old ^ new) & !(S_ISUID | S_ISGID) == 0;
+            return if kills_privs { Ok(mode) } else { Err(EPERM) };
+        }
+        if !cred.in_group(gid.unwrap_or(attr.gid)) {
+            return Ok(mode & !S_ISGID);
+        }
+        Ok(mode)
+    }
+
+    /// utimensat(2): setting explicit times is for the owner and root,
+    /// setting them to now also for anyone who may write the file.
+    fn may_set_times(&self, cred: &Credentials, attr: &ext4_rs::FileAttr, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> Result<(), i32> {
+        if cred.is_root() || cred.uid == attr.uid {
+            return Ok(());
+        }
+        let explicit = [atime, mtime]
+            .iter()
+            .any(|time| matches!(time, Some(TimeOrNow::SpecificTime(_))));
+        if explicit {
+            return Err(EPERM);
+        }
+        if atime.is_some() || mtime.is_some() {
+            self.check_access(cred, attr.ino, W_OK)?;
+        }
+        Ok(())
+    }
+
+    /// Drops setuid, and setgid if group execute is set, from a regular
+    /// file whose owner or contents changed, as the kernel does; setgid
+    /// without group execute marks mandatory locking and stays.
+    fn kill_privs(&self, ino: u64) {
+        let mut raw = self.layout.read_inode(&self.disk, ino as u32);
+        let mode = ondisk::le16(&raw, ondisk::I_MODE) as u32;
+        let mut kill = mode & S_ISUID;
+        if mode & (S_ISGID | S_IXGRP) == S_ISGID | S_IXGRP {
+            kill |= S_ISGID;
+        }
+        if mode & S_IFMT != S_IFREG || kill == 0 {
+            return;
+        }
+        ondisk::put16(&mut raw, ondisk::I_MODE, (mode & !kill) as u16);
+        self.layout.write_inode(&self.disk, ino as u32, &mut raw);
+    }
+
+    fn check_size(&self, ino: u64, size: u64) -> Result<(), i32> {
+        if self.raw_mode(ino) & S_IFMT == S_IFDIR {
+            return Err(EISDIR);
+        }
+        if size > MAX_FILE_SIZE {
+            return Err(EFBIG);
+        }
+        Ok(())
+    }
+
+    /// truncate(2). Shrinking frees the blocks past the new size and zeroes
+    /// the rest of its last block, so that growing again reads zeros;
+    /// growing leaves a hole.
+    fn truncate(&self, ino: u64, size: u64) -> Result<(), i32> {
+        self.check_size(ino, size)?;
+        let store = self.extents();
+        let old_size = store.size(ino as u32);
+        if size < old_size {
+            self.truncate_blocks(ino as u32, size)?;
+            let tail_end = size.next_multiple_of(BLOCK_SIZE as u64).min(old_size);
+            if size % BLOCK_SIZE as u64 != 0 {
+                let tree = store.load(ino as u32)?;
+                store.zero_bytes(&tree, size, tail_end);
+            }
+        }
+        store.set_size(ino as u32, size);
+        Ok(())
+    }
+
+    /// The body of getattr, for `inode` as already mapped from the root.
+    fn get_attr(&self, inode: u64) -> Result<FileAttr, i32> {
+        let _guard = self.inodes.shared(inode);
+        self.entry_attr(inode)
+    }
+
+    /// The body of mknod, for `parent` as already mapped from the root.
+    fn add_node(
+        &self,
+        req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+    ) -> Result<FileAttr, i32> {
+        let _guard = self.inodes.exclusive(&[parent]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        if let Err(e) = self
+            .check_dir_writable(parent)
+            .and_then(|_| self.permit(req, parent, W_OK | X_OK))
+            .and_then(|_| self.check_new_entry(parent, name))
+        {
+            log::warn!("mknod denied in parent {}: {}", parent, e);
+            return Err(e);
+        }
+        let attr = self.make_node(req, parent, name, mode, umask, rdev)?;
+        self.inode_table.lookup(attr.ino);
+        self.notify.entry_cached(parent, name, self.config.entry_ttl);
+        Ok(attr)
+    }
+
+    /// The body of mkdir, for `parent` as already mapped from the root.
+    fn add_dir(
+        &self,
+        req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<FileAttr, i32> {
+        let _guard = self.inodes.exclusive(&[parent]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        if let Err(e) = self
+            .check_dir_writable(parent)
+            .and_then(|_| self.permit(req, parent, W_OK | X_OK))
+            .and_then(|_| self.check_new_entry(parent, name))
+        {
+            log::warn!("mkdir denied in parent {}: {}", parent, e);
+            return Err(e);
+        }
+        let attr = self.make_dir(req, parent, name, mode, umask)?;
+        self.inode_table.lookup(attr.ino);
+        self.notify.entry_cached(parent, name, self.config.entry_ttl);
+        Ok(attr)
+    }
+
+    /// The body of setattr, for `inode` as already mapped from the root.
+    #[allow(clippy::too_many_arguments)]
+    fn set_attr(
+        &self,
+        req: &Caller,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+    ) -> Result<ext4_rs::FileAttr, i32> {
+        let _guard = self.inodes.exclusive(&[inode]);
+        // Truncation and the ACL rewrite of a chmod may allocate or free.
+        let _alloc = (size.is_some() || mode.is_some()).then(|| self.alloc.lock().unwrap());
+
+        let attr = match self.ext4.fuse_getattr(inode) {
+            Ok(attr) => attr,
+            Err(e) => {
+                log::warn!("setattr failed for ino {}: {:?}", inode, e);
+                return Err(errno::from_ext4(e));
+            }
+        };
+        let inode_flags = self.inode_flags(inode);
+        let changes_attrs = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || size.is_some()
+            || atime.is_some()
+            || mtime.is_some()
+            || ctime.is_some()
+            || crtime.is_some()
+            || chgtime.is_some()
+            || bkuptime.is_some();
+        let explicit_times = matches!(atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(mtime, Some(TimeOrNow::SpecificTime(_)));
+        let immutable = inode_flags & EXT4_IMMUTABLE_FL != 0 && changes_attrs;
+        let changes_contents_or_owner =
+            mode.is_some() || uid.is_some() || gid.is_some() || size.is_some() || explicit_times;
+        let append_only = inode_flags & EXT4_APPEND_FL != 0 && changes_contents_or_owner;
+        if changes_attrs || flags.is_some() {
+            if let Err(e) = self.check_read_write() {
+                log::warn!("setattr denied for ino {}: read-only mount", inode);
+                return Err(e);
+            }
+        }
+        if immutable || append_only {
+            log::warn!("setattr denied for ino {}: flags {:#x}", inode, inode_flags);
+            return Err(EPERM);
+        }
+
+        let cred = Credentials::from_request(req);
+        let mut mode = mode;
+        if !self.config.default_permissions {
+            let checked = self
+                .may_chown(&cred, &attr, uid, gid)
+                .and_then(|_| self.may_set_times(&cred, &attr, atime, mtime))
+                .and_then(|_| match size {
+                    // An open file was checked when it was opened.
+                    Some(_) if fh.is_none() => self.check_access(&cred, inode, W_OK),
+                    _ => Ok(()),
+                })
+                .and_then(|_| match mode {
+                    Some(m) => self.may_chmod(&cred, &attr, gid, m).map(Some),
+                    None => Ok(None),
+                });
+            match checked {
+                Ok(checked_mode) => mode = checked_mode,
+                Err(e) => {
+                    log::warn!("setattr denied for ino {}: {}", inode, e);
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(Err(e)) = size.map(|size| self.check_size(inode, size)) {
+            log::warn!("setattr: cannot truncate ino {} to {:?}: {}", inode, size, e);
+            return Err(e);
+        }
+
+        // chflags(2) bits, from macOS and the BSDs.
+        if let Some(flags) = flags {
+            let flags = iflags::from_bsd(inode_flags, flags);
+            if let Err(e) = self.set_inode_flags(req, inode, flags) {
+                log::warn!("setattr: failed to set flags of ino {}: {}", inode, e);
+                return Err(e);
+            }
+        }
+
+        // ext4_rs keeps whole seconds only, so the timestamps are written to
+        // the inode below. chgtime and bkuptime have no place in ext4.
+        let now = SystemTime::now();
+        let time_or_now = |t| match t {
+            TimeOrNow::SpecificTime(t) => t,
+            TimeOrNow::Now => now,
+        };
+        let atime = atime.map(time_or_now);
+        let mut mtime = mtime.map(time_or_now);
+        if size.is_some() && mtime.is_none() {
+            // truncate(2) updates mtime even when the size stays the same.
+            mtime = Some(now);
+        }
+        let chgtime_secs = chgtime.map(system_time_to_secs);
+        let bkuptime_secs = bkuptime.map(system_time_to_secs);
+
+        // The size is set below, freeing blocks as ext4_rs does not.
+        let r = self.ext4.fuse_setattr(
+            inode,
+            mode,
+            uid,
+            gid,
+            None,
+            None,
+            None,
+            None,
+            fh,
+            None,
+            chgtime_secs,
+            bkuptime_secs,
+            None,
+        );
+        if let Err(e) = r {
+            log::warn!("setattr failed for ino {}: {:?}", inode, e);
+            return Err(errno::from_ext4(e));
+        }
+        if let Some(size) = size {
+            if let Err(e) = self.truncate(inode, size) {
+                log::warn!("setattr: truncating ino {} to {} failed: {}", inode, size, e);
+                return Err(e);
+            }
+        }
+
+        // A new owner, or new contents written by someone other than root,
+        // drop setuid and setgid unless the mode is being set as well.
+        let chowned = uid.is_some_and(|uid| uid != attr.uid) || gid.is_some_and(|gid| gid != attr.gid);
+        if mode.is_none() && (chowned || (size.is_some() && !cred.is_root())) {
+            self.kill_privs(inode);
+        }
+
+        // A chmod also rewrites the owner, group/mask and other entries of
+        // the access ACL.
+        if let Some(mode) = mode {
+            if let Ok(Some(mut acl)) = self.get_acl(inode, XATTR_INDEX_POSIX_ACL_ACCESS) {
+                acl.chmod(mode);
+                if let Err(e) = self.set_acl(inode, XATTR_INDEX_POSIX_ACL_ACCESS, Some(&acl)) {
+                    log::warn!("setattr: failed to update ACL of ino {}: {}", inode, e);
+                }
+            }
+        }
+
+        // Times set here replace those lazytime holds back.
+        if atime.is_some() {
+            self.lazy_times.forget(inode, &[InodeTime::Access]);
+        }
+        if mtime.is_some() {
+            self.lazy_times.forget(inode, &[InodeTime::Modify]);
+        }
+        if changes_attrs {
+            self.lazy_times.forget(inode, &[InodeTime::Change]);
+        }
+        // Every change of attributes also updates ctime.
+        if changes_attrs {
+            let mut raw = self.layout.read_inode(&self.disk, inode as u32);
+            let times = [
+                (InodeTime::Access, atime),
+                (InodeTime::Modify, mtime),
+                (InodeTime::Change, Some(ctime.unwrap_or(now))),
+                (InodeTime::Create, crtime),
+            ];
+            for (which, time) in times {
+                if let Some(time) = time {
+                    ondisk::set_inode_time(&mut raw, which, time);
+                }
+            }
+            self.layout.write_inode(&self.disk, inode as u32, &mut raw);
+        }
+
+        self.ext4.fuse_getattr(inode).map_err(|e| {
+            log::error!("setattr: getattr failed after setattr for ino {}: {:?}", inode, e);
+            errno::from_ext4(e)
+        })
+    }
+
+    /// Immutable files take no writes at all, append-only files only
+    /// writes at or past their end.
+    fn check_writable(&self, ino: u64, offset: u64) -> Result<(), i32> {
+        self.check_read_write()?;
+        let flags = self.inode_flags(ino);
+        if flags & EXT4_IMMUTABLE_FL != 0 {
+            return Err(EPERM);
+        }
+        if flags & EXT4_APPEND_FL != 0 && offset < self.extents().size(ino as u32) {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// Whether writing `data` at `offset` leaves the bytes before the end
+    /// of a file that is not immutable as they are.
+    fn only_appends(&self, ino: u64, offset: u64, data: &[u8]) -> bool {
+        if self.inode_flags(ino) & EXT4_IMMUTABLE_FL != 0 {
+            return false;
+        }
+        let size = self.extents().size(ino as u32);
+        let len = size.saturating_sub(offset).min(data.len() as u64) as usize;
+        if len == 0 {
+            return true;
+        }
+        self.ext4
+            .fuse_read(ino, 0, offset as i64, len as u32, 0, None)
+            .is_ok_and(|old| old == data[..len])
+    }
+
+    /// No entries can be added to an immutable directory.
+    fn check_dir_writable(&self, dir: u64) -> Result<(), i32> {
+        self.check_read_write()?;
+        if self.inode_flags(dir) & EXT4_IMMUTABLE_FL != 0 {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// Checks the caller against `want` (R_OK/W_OK/X_OK) unless the kernel
+    /// does so itself under `default_permissions`.
+    fn permit(&self, req: &Caller, ino: u64, want: i32) -> Result<(), i32> {
+        if self.config.default_permissions {
+            return Ok(());
+        }
+        self.check_access(&Credentials::from_request(req), ino, want)
+    }
+
+    /// Checks the R_OK/W_OK/X_OK bits in `want` against the inode's access
+    /// ACL, or its mode bits when it has none.
+    fn check_access(&self, cred: &Credentials, ino: u64, want: i32) -> Result<(), i32> {
+        let attr = self.ext4.fuse_getattr(ino).map_err(errno::from_ext4)?;
+        let mode = self.raw_mode(ino);
+
+        if cred.is_root() {
+            // Root may execute only if some execute bit is set.
+            let exec_ok = want & X_OK == 0 || mode & S_IFMT == S_IFDIR || mode & 0o111 != 0;
+            return if exec_ok { Ok(()) } else { Err(EACCES) };
+        }
+
+        let want = (want & 7) as u16;
+        let in_group = |gid| cred.in_group(gid);
+        let granted = match self.get_acl(ino, XATTR_INDEX_POSIX_ACL_ACCESS)? {
+            Some(acl) => acl.permits(cred.uid, in_group, attr.uid, attr.gid, want),
+            None => {
+                let class = if cred.uid == attr.uid {
+                    mode >> 6
+                } else if in_group(attr.gid) {
+                    mode >> 3
+                } else {
+                    mode
+                };
+                (class as u16 & want) == want
+            }
+        };
+
+        if granted {
+            Ok(())
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    /// Removing or replacing `name` needs write and search permission on
+    /// `parent`. In a sticky directory the caller must also own the entry or
+    /// the directory.
+    fn may_delete(&self, req: &Caller, parent: u64, name: &OsStr) -> Result<(), i32> {
+        self.check_read_write()?;
+        // Neither the entry nor an immutable or append-only directory
+        // holding it may change, whoever checks the permissions.
+        let child = self.lookup_name(parent, name)?;
+        for ino in [parent, child.ino] {
+            if self.inode_flags(ino) & (EXT4_IMMUTABLE_FL | EXT4_APPEND_FL) != 0 {
+                return Err(EPERM);
+            }
+        }
+
+        if self.config.default_permissions {
+            return Ok(());
+        }
+        let cred = Credentials::from_request(req);
+        self.check_access(&cred, parent, W_OK | X_OK)?;
+
+        if self.raw_mode(parent) & S_ISVTX == 0 || cred.is_root() {
+            return Ok(());
+        }
+        let dir = self.ext4.fuse_getattr(parent).map_err(errno::from_ext4)?;
+        if cred.uid == dir.uid || cred.uid == child.uid {
+            Ok(())
+        } else {
+            Err(EPERM)
+        }
+    }
+
+    /// Permission checks of rename(2): deleting from the old parent,
+    /// creating in the new one, replacing an existing target and, for a
+    /// directory moved to a new parent, rewriting its "..".
+    fn may_rename(
+        &self,
+        req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), i32> {
+        let source = self.lookup_name(parent, name)?;
+        let target = self.lookup_name(newparent, newname);
+        if flags & RENAME_NOREPLACE != 0 && target.is_ok() {
+            return Err(EEXIST);
+        }
+
+        self.may_delete(req, parent, name)?;
+        if target.is_ok() {
+            self.may_delete(req, newparent, newname)?;
+        } else {
+            self.check_dir_writable(newparent)?;
+            self.permit(req, newparent, W_OK | X_OK)?;
+        }
+        if source.kind == InodeFileType::S_IFDIR && parent != newparent {
+            self.permit(req, source.ino, W_OK)?;
+        }
+        Ok(())
+    }
+
+    /// A new entry may not shadow an existing one.
+    fn check_new_entry(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        match self.lookup_name(parent, name) {
+            Ok(_) => Err(EEXIST),
+            Err(ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// unlink(2) takes anything but directories and rmdir(2) only empty
+    /// directories.
+    fn check_remove(&self, parent: u64, name: &OsStr, want_dir: bool) -> Result<(), i32> {
+        let attr = self.lookup_name(parent, name)?;
+        let is_dir = attr.kind == InodeFileType::S_IFDIR;
+        match (want_dir, is_dir) {
+            (true, false) => Err(ENOTDIR),
+            (false, true) => Err(EISDIR),
+            (true, true) if !self.is_empty_dir(attr.ino)? => Err(ENOTEMPTY),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_empty_dir(&self, ino: u64) -> Result<bool, i32> {
+        Ok(self
+            .list_dir(ino)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// Type checks of rename(2). Returns whether source and target are the
+    /// same inode, in which case there is nothing to do. RENAME_EXCHANGE
+    /// swaps two existing entries of any type.
+    fn check_rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<bool, i32> {
+        let source = self.lookup_name(parent, name)?;
+        let source_dir = source.kind == InodeFileType::S_IFDIR;
+        match self.lookup_name(newparent, newname) {
+            Ok(target) if target.ino == source.ino => return Ok(true),
+            Ok(_) if flags & RENAME_EXCHANGE != 0 => {}
+            Ok(target) => {
+                let target_dir = target.kind == InodeFileType::S_IFDIR;
+                if source_dir && !target_dir {
+                    return Err(ENOTDIR);
+                }
+                if !source_dir && target_dir {
+                    return Err(EISDIR);
+                }
+                if target_dir && !self.is_empty_dir(target.ino)? {
+                    return Err(ENOTEMPTY);
+                }
+            }
+            Err(ENOENT) if flags & RENAME_EXCHANGE == 0 => {}
+            Err(e) => return Err(e),
+        }
+
+        // A directory cannot become its own descendant.
+        if source_dir && parent != newparent {
+            let mut dir = newparent;
+            while dir != 2 {
+                if dir == source.ino {
+                    return Err(EINVAL);
+                }
+                dir = self.lookup_name(dir, OsStr::new(".."))?.ino;
+            }
+        }
+        Ok(false)
+    }
+
+    /// NUL-separated list of the attribute names visible to the caller.
+    fn xattr_names(&self, req: &Caller, ino: u64) -> Result<Vec<u8>, i32> {
+        let mut names = Vec::new();
+        for attr in self.xattrs().list(ino as u32)? {
+            if !xattr::is_listable(attr.index, req.uid()) {
+                continue;
+            }
+            if let Some(name) = attr.full_name() {
+                names.extend_from_slice(&name);
+                names.push(0);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Answers a getxattr/listxattr request, or only the size when `size` is 0.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// The FUSE operations, called by `Server` on its worker threads with the
+/// arguments fuser passes.
+#[allow(clippy::too_many_arguments)]
+impl Ext4Fuse {
+    fn init(&self, _req: &Caller, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Have the kernel pass fcntl and flock locks down to the lock manager.
+        if let Err(unsupported) = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS) {
+            log::warn!("kernel lacks lock capabilities {:#x}, locks stay local", unsupported);
+        }
+        let readdirplus = FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO;
+        if let Err(unsupported) = config.add_capabilities(readdirplus) {
+            log::warn!("kernel lacks readdirplus capabilities {:#x}", unsupported);
+        }
+        // chattr and lsattr open directories too.
+        if let Err(unsupported) = config.add_capabilities(FUSE_HAS_IOCTL_DIR) {
+            log::warn!("kernel lacks ioctls on directories {:#x}", unsupported);
+        }
+
+        // Fewer and larger requests for bulk I/O, each feature on its own
+        // so that one the kernel lacks does not hold back the others.
+        let io = [
+            ("writeback cache", self.config.writeback_cache, FUSE_WRITEBACK_CACHE),
+            ("async read", self.config.async_read, FUSE_ASYNC_READ),
+            ("splice", self.config.splice, FUSE_SPLICE_READ | FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE),
+        ];
+        for (feature, enabled, flags) in io {
+            if !enabled {
+                continue;
+            }
+            if let Err(unsupported) = config.add_capabilities(flags) {
+                log::warn!("kernel lacks {} capabilities {:#x}", feature, unsupported);
+            }
+        }
+        if let Err(nearest) = config.set_max_write(self.config.max_write) {
+            log::warn!("max_write {} not supported, using {}", self.config.max_write, nearest);
+            let _ = config.set_max_write(nearest);
+        }
+        if let Err(nearest) = config.set_max_readahead(self.config.max_readahead) {
+            log::warn!("max_readahead {} not supported, using {}", self.config.max_readahead, nearest);
+            let _ = config.set_max_readahead(nearest);
+        }
+
+        // A read-only mount leaves the image as it found it.
+        if self.config.read_only {
+            return Ok(());
+        }
+        let mountpoint = std::fs::canonicalize(&self.config.mountpoint)
+            .map_or_else(|_| self.config.mountpoint.clone(), |path| path.display().to_string());
+        let state = superblock::mark_mounted(&self.disk, &mountpoint);
+        let _ = self.mount_state.set(state);
+        // Inodes a crash left unlinked but allocated.
+        self.release_orphans();
+        Ok(())
+    }
+
+    /// The kernel holds no references once unmounted, so orphans left by
+    /// files still open are freed now, before the filesystem is flushed
+    /// and marked clean.
+    fn destroy(&self) {
+        if self.config.read_only {
+            return;
+        }
+        for (ino, times) in self.lazy_times.take_all() {
+            self.flush_times(ino, &times);
+        }
+        self.release_orphans();
+        if let Some(&state) = self.mount_state.get() {
+            superblock::mark_unmounted(&self.disk, state);
+        }
+        if let Err(e) = self.sync(false) {
+            log::error!("flushing {} at unmount failed: {}", IMAGE_PATH, e);
+        }
+    }
+
+    fn lookup(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        log::info!("lookup parent: {}, name: {:?}", parent, name);
+        // fuse use 1 as root inode
+        let parent = match parent {
+            // root
+            1 => 2,
+            _ => parent,
+        };
+        let _guard = self.inodes.shared(parent);
+
+        if let Err(e) = self.permit(_req, parent, X_OK) {
+            log::warn!("lookup denied in parent {}: {}", parent, e);
+            reply.error(e);
+            return;
+        }
+
+        let file_attr = match self.lookup_name(parent, name) {
+            Ok(file_attr) => file_attr,
+            // An entry with inode 0 has the kernel cache the miss.
+            Err(ENOENT) if !self.config.negative_ttl.is_zero() => {
+                log::info!("lookup found no {:?} in parent {}", name, parent);
+                self.notify.entry_cached(parent, name, self.config.negative_ttl);
+                reply.entry(&self.config.negative_ttl, &negative_entry(), 0);
+                return;
+            }
+            Err(e) => {
+                log::info!("lookup failed for name {:?} in parent {}: {}", name, parent, e);
+                reply.error(e);
+                return;
+            }
+        };
+        log::info!("lookup successful: ino={}, size={}, kind={:?}", file_attr.ino, file_attr.size, file_attr.kind);
+
+        let attr = self.file_attr(&file_attr);
+
+        self.inode_table.lookup(attr.ino);
+        self.notify.entry_cached(parent, name, self.config.entry_ttl);
+        reply.entry(&self.config.entry_ttl, &attr, 0);
+    }
+
+    /// The kernel dropped `nlookup` references to an inode. An unlinked
+    /// inode is freed with its last one.
+    fn forget(&self, _req: &Caller, ino: u64, nlookup: u64) {
+        log::info!("forget ino: {}, nlookup: {}", ino, nlookup);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        if !self.inode_table.forget(inode, nlookup) {
+            return;
+        }
+        let _guard = self.inodes.exclusive(&[inode]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        // Looked up again.
+        if self.inode_table.is_referenced(inode) {
+            return;
+        }
+        if self.links_count(inode as u32) != 0 {
+            // Nothing is left to fsync it, so held back timestamps go now.
+            self.flush_times(inode, &self.lazy_times.take(inode));
+            return;
+        }
+        match self.release_orphan(inode as u32) {
+            Ok(()) => log::info!("freed orphan inode {}", inode),
+            // Freed already.
+            Err(ENOENT) => {},
+            Err(e) => log::warn!("failed to free orphan inode {}: {}", inode, e),
+        }
+    }
+
+    fn batch_forget(&self, _req: &Caller, nodes: &[(u64, u64)]) {
+        for &(ino, nlookup) in nodes {
+            self.forget(_req, ino, nlookup);
+        }
+    }
+
+    fn getattr(&self, _req: &Caller, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        log::info!("getattr ino: {}, fh: {:?}", ino, _fh);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        match self.get_attr(inode) {
+            Ok(attr) => {
+                log::info!("getattr successful: ino={}, size={}, kind={:?}", attr.ino, attr.size, attr.kind);
+                reply.attr(&self.config.attr_ttl, &attr);
+            }
+            Err(e) => {
+                log::warn!("getattr failed for ino {}: {}", ino, e);
+                reply.error(e);
+            }
+        }
+    }
+
+    fn setattr(
+        &self,
+        _req: &Caller,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        log::info!("setattr ino: {}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}", 
+                   inode, mode, uid, gid, size);
+        let inode = match inode {
+            // root
+            1 => 2,
+            _ => inode,
+        };
+        let r = self.set_attr(
+            _req, inode, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags,
+        );
+        match r {
+            Ok(file_attr) => {
+                log::info!("setattr successful for ino {}", inode);
+                reply.attr(&self.config.attr_ttl, &self.file_attr(&file_attr));
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        log::info!("read ino: {}, fh: {}, offset: {}, size: {}, flags: {}", 
+                   ino, fh, offset, size, flags);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+        let before = self.layout.read_inode(&self.disk, inode as u32);
+        let r = self.ext4.fuse_read(inode, fh, offset, size, flags, lock);
+        if r.is_ok() {
+            self.note_access(inode, &before);
+        } else {
+            self.restore_atime(inode, &before);
+        }
+        match r {
+            Ok(mut data) => {
+                // Preallocated blocks hold whatever was on disk before.
+                if let Err(e) = self
+                    .extents()
+                    .mask_unwritten(inode as u32, offset as u64, &mut data)
+                {
+                    log::warn!("read failed for ino {}: {}", ino, e);
+                    return reply.error(e);
+                }
+                log::info!("read successful: {} bytes returned", data.len());
+                reply.data(&data)
+            },
+            Err(e) => {
+                log::warn!("read failed for ino {}: {:?}", ino, e);
+                reply.error(errno::from_ext4(e))
+            },
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        log::info!("readdir ino: {}, fh: {}, offset: {}", ino, fh, offset);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        let entries = match self.dir_snapshot(inode, fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("readdir failed for ino {}: {}", ino, e);
+                return reply.error(e);
+            },
+        };
+        log::info!("readdir found {} entries", entries.len());
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            log::debug!("readdir entry: name={:?}, inode={}, kind={:?}", entry.name, entry.ino, entry.kind);
+            if reply.add(entry.ino, (i + 1) as i64, entry.kind, &entry.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    /// Read a directory along with the attributes of its entries.
+    fn readdirplus(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        log::info!("readdirplus ino: {}, fh: {}, offset: {}", ino, fh, offset);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        let entries = match self.dir_snapshot(inode, fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("readdirplus failed for ino {}: {}", ino, e);
+                return reply.error(e);
+            },
+        };
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            // Entries removed since the snapshot are left out.
+            let Ok(attr) = self.ext4.fuse_getattr(entry.ino) else {
+                continue;
+            };
+            let attr = self.file_attr(&attr);
+            if reply.add(entry.ino, (i + 1) as i64, &entry.name, &self.config.entry_ttl, &attr, 0) {
+                break;
+            }
+            // The kernel takes no reference for "." and "..".
+            if entry.name != "." && entry.name != ".." {
+                self.inode_table.lookup(entry.ino);
+                self.notify.entry_cached(inode, &entry.name, self.config.entry_ttl);
+            }
+        }
+        reply.ok();
+    }
+
+    fn write(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        log::info!("write ino: {}, fh: {}, offset: {}, data_len: {}, write_flags: {}, flags: {}", 
+                   ino, fh, offset, data.len(), write_flags, flags);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.exclusive(&[inode]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        // The writeback cache flushes whole pages, so an append to an
+        // append-only file may start below its end, with the bytes that
+        // are already there.
+        let checked = match self.check_writable(inode, offset as u64) {
+            Err(EPERM) if write_flags & FUSE_WRITE_CACHE != 0 && self.only_appends(inode, offset as u64, data) => Ok(()),
+            checked => checked,
+        };
+        if let Err(e) = checked {
+            log::warn!("write denied for ino {}: {}", ino, e);
+            return reply.error(e);
+        }
+        if let Err(e) = self
+            .extents()
+            .prepare_write(inode as u32, offset as u64, data.len() as u64)
+        {
+            log::warn!("write failed for ino {}: {}", ino, e);
+            return reply.error(e);
+        }
+
+        let r = self
+            .ext4
+            .fuse_write(inode, fh, offset, data, write_flags, flags, lock_owner);
+        match r {
+            Ok(size) => {
+                log::info!("write successful: {} bytes written", size);
+                if size > 0 {
+                    self.touch_data(inode);
+                    // Without CAP_FSETID, writing drops setuid and setgid.
+                    if _req.uid() != 0 {
+                        self.kill_privs(inode);
+                    }
+                }
+                reply.written(size as u32)
+            },
+            Err(e) => {
+                log::warn!("write failed for ino {}: {:?}", ino, e);
+                reply.error(errno::from_ext4(e))
+            },
+        }
+    }
+
+    /// Remove a file.
+    fn unlink(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        log::info!("unlink parent: {}, name: {:?}", parent, name);
+        let parent = match parent {
+            // root
+            1 => 2,
+            _ => parent,
+        };
+        let _guard = self.lock_entries(&[(parent, name)]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        if let Err(e) = self
+            .may_delete(_req, parent, name)
+            .and_then(|_| self.check_remove(parent, name, false))
+        {
+            log::warn!("unlink denied for {:?}: {}", name, e);
+            reply.error(e);
+            return;
+        }
+
+        let child = self.lookup_name(parent, name);
+        let held = self.hold_unlinked(parent, name);
+        let prepared = self.prepare_removal(parent, name);
+        let r = match name.to_str() {
+            Some(utf8) => self.ext4.fuse_unlink(parent, utf8).map(drop).map_err(errno::from_ext4),
+            None => self.remove_link(parent, name),
+        };
+        match r {
+            Ok(_) => {
+                log::info!("unlink successful for {:?}", name);
+                self.touch(parent, &[InodeTime::Modify, InodeTime::Change]);
+                // Other links keep the inode, whose link count changed.
+                if let Some(child) = child.ok().filter(|child| child.nlink > 1) {
+                    self.touch(child.ino, &[InodeTime::Change]);
+                }
+                if let Some(ino) = held {
+                    self.orphan(ino);
+                }
+                reply.ok()
+            },
+            Err(e) => {
+                log::warn!("unlink failed for {:?}: {}", name, e);
+                self.undo_removal(prepared);
+                self.unhold(held);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Create file node.
+    /// Create a regular file, character device, block device, fifo or socket node.
+    fn mknod(
+        &self,
+        _req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        log::info!("mknod parent: {}, name: {:?}, mode: {:o}, umask: {:o}, rdev: {}", 
+                   parent, name, mode, umask, rdev);
+        let parent = match parent {
+            // root
+            1 => 2,
+            _ => parent,
+        };
+        match self.add_node(_req, parent, name, mode, umask, rdev) {
+            Ok(attr) => {
+                log::info!("mknod successful: created inode {}", attr.ino);
+                reply.entry(&self.config.entry_ttl, &attr, 0);
+            }
+            Err(e) => {
+                log::warn!("mknod failed for {:?}: {}", name, e);
+                reply.error(e);
+            }
+        }
+    }
+
+    fn mkdir(
+        &self,
+        _req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        log::info!("mkdir parent: {}, name: {:?}, mode: {:o}, umask: {:o}", parent, name, mode, umask);
+        let parent = match parent {
+            // root
+            1 => 2,
+            _ => parent,
+        };
+        match self.add_dir(_req, parent, name, mode, umask) {
+            Ok(attr) => {
+                log::info!("mkdir successful: created directory inode {}", attr.ino);
+                reply.entry(&self.config.entry_ttl, &attr, 0);
+            }
+            Err(e) => {
+                log::warn!("mkdir failed for {:?}: {}", name, e);
+                reply.error(e);
+            }
+        }
+    }
+
+    fn rmdir(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        log::info!("rmdir parent: {}, name: {:?}", parent, name);
+        let parent = match parent {
+            // root
+            1 => 2,
+            _ => parent,
+        };
+        let _guard = self.lock_entries(&[(parent, name)]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        if let Err(e) = self
+            .may_delete(_req, parent, name)
+            .and_then(|_| self.check_remove(parent, name, true))
+        {
+            log::warn!("rmdir denied for {:?}: {}", name, e);
+            reply.error(e);
+            return;
+        }
 
-        reply.entry(&TTL, &attr, 0);
+        let parent_links = self.links_count(parent as u32);
+        let r = match name.to_str() {
+            Some(utf8) => self.ext4.fuse_rmdir(parent, utf8).map(drop).map_err(errno::from_ext4),
+            None => self.remove_link(parent, name),
+        };
+        match r {
+            Ok(_) => {
+                log::info!("rmdir successful for {:?}", name);
+                // The removed directory's ".." no longer links the parent.
+                if self.links_count(parent as u32) == parent_links {
+                    self.add_dir_link(parent as u32, false);
+                }
+                self.touch(parent, &[InodeTime::Modify, InodeTime::Change]);
+                reply.ok()
+            },
+            Err(e) => {
+                log::warn!("rmdir failed for {:?}: {}", name, e);
+                reply.error(e)
+            },
+        }
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        log::info!("rmdir parent: {}, name: {:?}", parent, name);
+    /// Rename a file.
+    fn rename(
+        &self,
+        _req: &Caller,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        log::info!("rename parent: {}, name: {:?}, newparent: {}, newname: {:?}, flags: {}",
+                   parent, name, newparent, newname, flags);
         let parent = match parent {
             // root
             1 => 2,
             _ => parent,
         };
+        let newparent = match newparent {
+            // root
+            1 => 2,
+            _ => newparent,
+        };
+        let _guard = self.lock_entries(&[(parent, name), (newparent, newname)]);
+        // Also keeps the directory tree still for the loop check.
+        let _alloc = self.alloc.lock().unwrap();
+
+        if let Err(e) = self.may_rename(_req, parent, name, newparent, newname, flags) {
+            log::warn!("rename denied for {:?}: {}", name, e);
+            reply.error(e);
+            return;
+        }
+        match self.check_rename(parent, name, newparent, newname, flags) {
+            Ok(false) => {},
+            Ok(true) => {
+                log::info!("rename of {:?} onto the same inode, nothing to do", name);
+                reply.ok();
+                return;
+            },
+            Err(e) => {
+                log::warn!("rename of {:?} to {:?} rejected: {}", name, newname, e);
+                reply.error(e);
+                return;
+            },
+        }
 
-        let r = self.ext4.fuse_rmdir(parent, name.to_str().unwrap());
+        // A replaced target the kernel still references becomes an orphan.
+        let (held, prepared) = match flags & RENAME_EXCHANGE {
+            0 => (self.hold_unlinked(newparent, newname), self.prepare_removal(newparent, newname)),
+            _ => (None, None),
+        };
+        let r = match (name.to_str(), newname.to_str()) {
+            (Some(utf8), Some(new_utf8)) => self
+                .ext4
+                .fuse_rename(parent, utf8, newparent, new_utf8, flags)
+                .map(drop)
+                .map_err(errno::from_ext4),
+            _ => self.rename_entry(parent, name, newparent, newname, flags),
+        };
         match r {
             Ok(_) => {
-                log::info!("rmdir successful for {:?}", name);
+                log::info!("rename successful for {:?} -> {:?}", name, newname);
+                self.touch(parent, &[InodeTime::Modify, InodeTime::Change]);
+                if newparent != parent {
+                    self.touch(newparent, &[InodeTime::Modify, InodeTime::Change]);
+                }
+                // Keep the entry type of a moved special file.
+                if let Ok(attr) = self.lookup_name(newparent, newname) {
+                    self.touch(attr.ino, &[InodeTime::Change]);
+                    let mode = self.raw_mode(attr.ino);
+                    if is_special(mode) {
+                        let ft = dirent::file_type(mode);
+                        let (dir, ino) = (newparent as u32, attr.ino as u32);
+                        let r = dirent::set_file_type(&self.extents(), dir, newname.as_bytes(), ino, ft);
+                        if let Err(e) = r {
+                            log::warn!("rename: failed to set entry type of {:?}: {}", newname, e);
+                        }
+                    }
+                }
+                if let Some(ino) = held {
+                    self.orphan(ino);
+                }
+                reply.ok()
+            },
+            Err(e) => {
+                log::warn!("rename failed for {:?}: {}", name, e);
+                self.undo_removal(prepared);
+                self.unhold(held);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Open a file, checking the access mode against the caller.
+    fn open(&self, _req: &Caller, ino: u64, flags: i32, reply: ReplyOpen) {
+        log::info!("open ino: {}, flags: {:#o}", ino, flags);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        let mut want = match flags & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            O_RDWR => R_OK | W_OK,
+            _ => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if flags & O_TRUNC != 0 {
+            want |= W_OK;
+        }
+
+        if want & W_OK != 0 {
+            let inode_flags = self.inode_flags(inode);
+            let append_only = inode_flags & EXT4_APPEND_FL != 0
+                && (flags & O_APPEND == 0 || flags & O_TRUNC != 0);
+            if inode_flags & EXT4_IMMUTABLE_FL != 0 || append_only {
+                log::warn!("open denied for ino {}: flags {:#x}", ino, inode_flags);
+                reply.error(EPERM);
+                return;
+            }
+        }
+
+        match self.permit(_req, inode, want) {
+            Ok(()) => reply.opened(0, 0),
+            Err(e) => {
+                log::warn!("open denied for ino {}: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Open a directory for reading.
+    fn opendir(&self, _req: &Caller, ino: u64, flags: i32, reply: ReplyOpen) {
+        log::info!("opendir ino: {}, flags: {:#o}", ino, flags);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        match self.permit(_req, inode, R_OK) {
+            Ok(()) => reply.opened(self.dirs.lock().unwrap().open(), 0),
+            Err(e) => {
+                log::warn!("opendir denied for ino {}: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Release an open directory, dropping its snapshot.
+    fn releasedir(&self, _req: &Caller, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        log::info!("releasedir ino: {}, fh: {}, flags: {:#o}", ino, fh, flags);
+        self.dirs.lock().unwrap().release(fh);
+        reply.ok();
+    }
+
+    /// Flush method, called on each close() of an open file. Writes go to
+    /// the image as they come, so there is nothing to write out; close(2)
+    /// only learns of one that failed. Syncing is left to fsync(2).
+    fn flush(&self, _req: &Caller, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        log::info!("flush ino: {}, fh: {}, lock_owner: {}", ino, fh, lock_owner);
+        // Closing any descriptor drops the process's record locks on the file.
+        self.locks.lock().unwrap().release_owner(ino, lock_owner);
+        match self.deferred_error() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Release an open file, after its last descriptor is closed.
+    fn release(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        log::info!("release ino: {}, fh: {}, flags: {}, lock_owner: {:?}, flush: {}",
+                   ino, fh, flags, lock_owner, flush);
+        // Set when the file holds a flock lock.
+        if let Some(owner) = lock_owner {
+            self.locks.lock().unwrap().release_owner(ino, owner);
+        }
+        reply.ok();
+    }
+
+    /// Synchronize file contents.
+    fn fsync(&self, _req: &Caller, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        log::info!("fsync ino: {}, fh: {}, datasync: {}", ino, fh, datasync);
+        self.sync_times(ino, datasync);
+        match self.sync(datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir(&self, _req: &Caller, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        log::info!("fsyncdir ino: {}, fh: {}, datasync: {}", ino, fh, datasync);
+        self.sync_times(ino, datasync);
+        match self.sync(datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Preallocate or deallocate space in a file.
+    fn fallocate(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        log::info!("fallocate ino: {}, fh: {}, offset: {}, length: {}, mode: {:#x}",
+                   ino, fh, offset, length, mode);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.exclusive(&[inode]);
+        let _alloc = self.alloc.lock().unwrap();
+        if offset < 0 || length <= 0 {
+            return reply.error(EINVAL);
+        }
+
+        // Only plain preallocation leaves existing contents alone.
+        let preallocates = mode & !fallocate::FALLOC_FL_KEEP_SIZE == 0;
+        let inode_flags = self.inode_flags(inode);
+        let append_only = inode_flags & EXT4_APPEND_FL != 0 && !preallocates;
+        if inode_flags & EXT4_IMMUTABLE_FL != 0 || append_only {
+            return reply.error(EPERM);
+        }
+
+        let store = self.extents();
+        let r = fallocate::fallocate(&store, inode as u32, offset as u64, length as u64, mode);
+        match r {
+            Ok(()) => {
+                self.touch_data(inode);
+                reply.ok()
+            },
+            Err(e) => {
+                log::warn!("fallocate failed for ino {}: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Copy a range of data from one file to another inside the image.
+    fn copy_file_range(
+        &self,
+        _req: &Caller,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        log::info!("copy_file_range ino_in: {}, fh_in: {}, offset_in: {}, ino_out: {}, fh_out: {}, offset_out: {}, len: {}, flags: {}",
+                   ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags);
+        let _guard = self.inodes.lock(&[ino_in], &[ino_out]);
+        let _alloc = self.alloc.lock().unwrap();
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            return reply.error(EINVAL);
+        }
+
+        if let Err(e) = self.check_writable(ino_out, offset_out as u64) {
+            log::warn!("copy_file_range denied for ino {}: {}", ino_out, e);
+            return reply.error(e);
+        }
+
+        // The reply can only report a u32 count.
+        let len = len.min(u32::MAX as u64);
+        let r = copy::copy_file_range(
+            &self.extents(),
+            ino_in as u32,
+            offset_in as u64,
+            ino_out as u32,
+            offset_out as u64,
+            len,
+        );
+        match r {
+            Ok(copied) => {
+                log::info!("copy_file_range successful: {} bytes copied", copied);
+                if copied > 0 {
+                    self.touch_data(ino_out);
+                    // Copied bytes are written bytes, see write.
+                    if _req.uid() != 0 {
+                        self.kill_privs(ino_out);
+                    }
+                }
+                reply.written(copied as u32)
+            },
+            Err(e) => {
+                log::warn!("copy_file_range failed from ino {} to ino {}: {}", ino_in, ino_out, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Get or set the inode flags for lsattr and chattr.
+    fn ioctl(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        log::info!("ioctl ino: {}, fh: {}, flags: {}, cmd: {:#x}, in_len: {}, out_size: {}",
+                   ino, fh, flags, cmd, in_data.len(), out_size);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = match cmd {
+            iflags::FS_IOC_SETFLAGS | iflags::FS_IOC32_SETFLAGS => self.inodes.exclusive(&[inode]),
+            _ => self.inodes.shared(inode),
+        };
+
+        match cmd {
+            iflags::FS_IOC_GETFLAGS | iflags::FS_IOC32_GETFLAGS => {
+                let flags = iflags::visible(self.inode_flags(inode)) as u64;
+                let out = flags.to_ne_bytes();
+                reply.ioctl(0, &out[..(out_size as usize).min(out.len())])
+            }
+            iflags::FS_IOC_SETFLAGS | iflags::FS_IOC32_SETFLAGS => {
+                let Some(bytes) = in_data.get(..4) else {
+                    return reply.error(EINVAL);
+                };
+                let flags = u32::from_ne_bytes(bytes.try_into().unwrap());
+                match self.set_inode_flags(_req, inode, flags) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(e) => {
+                        log::warn!("ioctl: failed to set flags of ino {}: {}", ino, e);
+                        reply.error(e)
+                    },
+                }
+            }
+            _ => reply.error(ENOTTY),
+        }
+    }
+
+    /// Reposition read/write file offset, for SEEK_DATA and SEEK_HOLE.
+    fn lseek(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        log::info!("lseek ino: {}, fh: {}, offset: {}, whence: {}", ino, fh, offset, whence);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+        if offset < 0 {
+            return reply.error(ENXIO);
+        }
+
+        match self.extents().seek(inode as u32, offset as u64, whence) {
+            Ok(pos) => reply.offset(pos as i64),
+            Err(e) => {
+                log::info!("lseek for ino {} found nothing: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        log::info!("getlk ino: {}, fh: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {}",
+                   ino, fh, lock_owner, start, end, typ, pid);
+        let lock = Lock { start, end, typ, owner: lock_owner, pid };
+        match self.locks.lock().unwrap().conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, F_UNLCK, 0),
+        }
+    }
+
+    /// Acquire, modify or release a POSIX file lock, or a flock lock.
+    fn setlk(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        log::info!("setlk ino: {}, fh: {}, lock_owner: {}, start: {}, end: {}, typ: {}, pid: {}, sleep: {}",
+                   ino, fh, lock_owner, start, end, typ, pid, sleep);
+        let lock = Lock { start, end, typ, owner: lock_owner, pid };
+        if sleep {
+            // Replied to once the lock is granted.
+            return self.locks.lock().unwrap().set_wait(ino, lock, reply);
+        }
+        match self.locks.lock().unwrap().set(ino, lock) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                log::info!("setlk for ino {} refused: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Check file access permissions for access(2).
+    fn access(&self, _req: &Caller, ino: u64, mask: i32, reply: ReplyEmpty) {
+        log::info!("access ino: {}, mask: {}", ino, mask);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        let r = if mask == F_OK {
+            self.ext4.fuse_getattr(inode).map(|_| ()).map_err(errno::from_ext4)
+        } else {
+            let cred = Credentials::from_request(_req);
+            self.check_access(&cred, inode, mask)
+        };
+        match r {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        log::info!("setxattr ino: {}, name: {:?}, size: {}, flags: {}, position: {}",
+                   ino, name, value.len(), flags, position);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.exclusive(&[inode]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        match self.xattr_set(_req, inode, name, Some(value), flags) {
+            Ok(()) => {
+                log::info!("setxattr successful for {:?}", name);
+                self.touch(inode, &[InodeTime::Change]);
+                reply.ok()
+            },
+            Err(e) => {
+                log::warn!("setxattr failed for {:?}: {}", name, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Get an extended attribute.
+    fn getxattr(
+        &self,
+        _req: &Caller,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        log::info!("getxattr ino: {}, name: {:?}, size: {}", ino, name, size);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        match self.xattr_get(_req, inode, name) {
+            Ok(value) => {
+                log::info!("getxattr successful: {} bytes", value.len());
+                reply_xattr(reply, size, &value)
+            },
+            Err(e) => {
+                log::debug!("getxattr failed for {:?}: {}", name, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// List extended attribute names.
+    fn listxattr(&self, _req: &Caller, ino: u64, size: u32, reply: ReplyXattr) {
+        log::info!("listxattr ino: {}, size: {}", ino, size);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.shared(inode);
+
+        match self.xattr_names(_req, inode) {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(e) => {
+                log::warn!("listxattr failed for ino {}: {}", ino, e);
+                reply.error(e)
+            },
+        }
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&self, _req: &Caller, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        log::info!("removexattr ino: {}, name: {:?}", ino, name);
+        let inode = match ino {
+            // root
+            1 => 2,
+            _ => ino,
+        };
+        let _guard = self.inodes.exclusive(&[inode]);
+        let _alloc = self.alloc.lock().unwrap();
+
+        match self.xattr_set(_req, inode, name, None, 0) {
+            Ok(()) => {
+                log::info!("removexattr successful for {:?}", name);
+                self.touch(inode, &[InodeTime::Change]);
                 reply.ok()
             },
             Err(e) => {
-                log::warn!("rmdir failed for {:?}: {:?}", name, e);
-                reply.error(ENOENT)
+                log::warn!("removexattr failed for {:?}: {}", name, e);
+                reply.error(e)
             },
         }
     }
@@ -688,8 +2982,53 @@ impl Filesystem for Ext4Fuse {
 //         .as_secs() as u32
 // }
 
-fn timestamp_to_system_time(timestamp: u32) -> SystemTime {
-    UNIX_EPOCH + Duration::from_secs(timestamp as u64)
+fn dir_entry_kind(de_type: u8) -> FileType {
+    match de_type {
+        dirent::EXT4_FT_DIR => FileType::Directory,
+        dirent::EXT4_FT_CHRDEV => FileType::CharDevice,
+        dirent::EXT4_FT_BLKDEV => FileType::BlockDevice,
+        dirent::EXT4_FT_FIFO => FileType::NamedPipe,
+        dirent::EXT4_FT_SOCK => FileType::Socket,
+        dirent::EXT4_FT_SYMLINK => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// The attributes of a negative entry, which the kernel ignores.
+fn negative_entry() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        blksize: BLOCK_SIZE as u32,
+    }
+}
+
+fn is_special(mode: u32) -> bool {
+    matches!(mode & S_IFMT, S_IFCHR | S_IFBLK | S_IFIFO | S_IFSOCK)
+}
+
+fn file_type(mode: u32) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::NamedPipe,
+        S_IFSOCK => FileType::Socket,
+        S_IFLNK => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
 }
 
 fn system_time_to_secs(time: SystemTime) -> u32 {
@@ -705,34 +3044,82 @@ fn main() {
     
     log::info!("Starting EXT4 FUSE filesystem");
 
-    let disk = Arc::new(Disk {});
-    log::info!("Created disk device for ex4.img");
+    let args: Vec<String> = env::args().collect();
+    // Inspecting a file needs no mount, and leaves the image as it is.
+    if let [_, flag, path] = &args[..] {
+        if flag == "--extents" {
+            let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+            disk.set_read_only();
+            let config = Config { read_only: true, ..Config::default() };
+            let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, config);
+            match fs.extent_map(Path::new(path)) {
+                Ok(map) => print!("{}", map),
+                Err(e) => {
+                    eprintln!("{}: {}", path, io::Error::from_raw_os_error(e));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+    let mut config = Config::from_args(&args).unwrap_or_else(|e| panic!("{}", e));
+
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    log::info!("Created disk device for {}", IMAGE_PATH);
+
+    // ext4_rs knows nothing of most features, so the image is checked
+    // before it gets to read or write it.
+    let features = features::Features::from_superblock(&disk.read_offset(ondisk::SUPERBLOCK_OFFSET));
+    features.report();
+    if features.unsupported_incompat() != 0 {
+        log::error!("refusing to mount {}", IMAGE_PATH);
+        std::process::exit(1);
+    }
+    if features.unsupported_ro_compat() != 0 && !config.read_only {
+        log::warn!("mounting {} read-only", IMAGE_PATH);
+        config.read_only = true;
+    }
+    if config.read_only {
+        disk.set_read_only();
+    }
     
-    let ext4 = Ext4::open(disk);
+    let ext4 = Ext4::open(disk.clone());
     log::info!("Opened EXT4 filesystem");
     
-    let ext4_fuse = Ext4Fuse::new(ext4);
-    // log::info!("Created FUSE filesystem wrapper");
-
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        panic!("No mount point specified!");
-    }
-    let mountpoint = &args[1];
+    let mountpoint = config.mountpoint.clone();
     // log::info!("Mount point: {}", mountpoint);
 
     let mut options = vec![
-        MountOption::RW,
+        if config.read_only { MountOption::RO } else { MountOption::RW },
         MountOption::FSName("ext4_test".to_string()),
     ];
 
     options.push(MountOption::AutoUnmount);
     options.push(MountOption::AllowRoot);
+    if config.default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
     
-    log::info!("Mount options: RW, FSName=ext4_test, AutoUnmount, AllowRoot");
-    log::info!("Mounting filesystem at {}", mountpoint);
+    log::info!("Mount options: {:?}", options);
+
+    let threads = match config.threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let ext4_fuse = Ext4Fuse::new(ext4, disk, config);
+    // log::info!("Created FUSE filesystem wrapper");
+
+    log::info!("Mounting filesystem at {} with {} worker threads", mountpoint, threads);
     
-    fuser::mount2(ext4_fuse, mountpoint, &options).unwrap();
+    let server = Server::new(ext4_fuse, threads);
+    let fs = server.fs();
+    let mut session = fuser::Session::new(server, &mountpoint, &options).unwrap();
+    fs.notify.attach(session.notifier());
+    std::thread::Builder::new()
+        .name("ext4-image-watch".to_string())
+        .spawn(move || fs.watch_image())
+        .unwrap();
+    session.run().unwrap();
     
     log::info!("Filesystem mounted successfully");
 }
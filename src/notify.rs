@@ -0,0 +1,86 @@
+//! Invalidation of the kernel's caches, for changes to the image that did
+//! not go through the mount, such as offline tools writing it meanwhile.
+//! Without it the kernel would serve them stale until the TTLs run out.
+
+use crate::ENOENT;
+use fuser::Notifier;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct Notify {
+    /// Set once the session is up.
+    notifier: OnceLock<Notifier>,
+    /// Names replied to the kernel, found or not, with when and for how
+    /// long it may cache them.
+    entries: Mutex<HashMap<(u64, OsString), (Instant, Duration)>>,
+}
+
+impl Notify {
+    pub fn attach(&self, notifier: Notifier) {
+        let _ = self.notifier.set(notifier);
+    }
+
+    /// Drops the attributes and cached pages the kernel holds for `ino`.
+    pub fn inode(&self, ino: u64) {
+        let Some(notifier) = self.notifier.get() else {
+            return;
+        };
+        match notifier.inval_inode(ino, 0, 0) {
+            // The kernel had already dropped it.
+            Err(e) if e.raw_os_error() == Some(ENOENT) => {}
+            Err(e) => log::warn!("failed to invalidate inode {}: {}", ino, e),
+            Ok(()) => {}
+        }
+    }
+
+    /// Notes that the kernel may cache `name` in `parent` for `ttl`.
+    pub fn entry_cached(&self, parent: u64, name: &OsStr, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let key = (parent, name.to_owned());
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), ttl));
+    }
+
+    /// Forgets names whose TTL ran out, which the kernel looks up again.
+    pub fn expire_entries(&self) {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, (at, ttl)| now.duration_since(*at) < *ttl);
+    }
+
+    /// Takes the names the kernel may still have cached.
+    pub fn take_entries(&self) -> Vec<(u64, OsString)> {
+        self.expire_entries();
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries.into_keys().collect()
+    }
+
+    /// Drops every name the kernel may still have cached.
+    pub fn entries(&self) {
+        let entries = self.take_entries();
+        let Some(notifier) = self.notifier.get() else {
+            return;
+        };
+        for (parent, name) in entries {
+            // The kernel knows the root as 1.
+            let parent = match parent {
+                2 => 1,
+                _ => parent,
+            };
+            match notifier.inval_entry(parent, &name) {
+                Err(e) if e.raw_os_error() == Some(ENOENT) => {}
+                Err(e) => log::warn!("failed to invalidate {:?} in {}: {}", name, parent, e),
+                Ok(()) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Raw access to on-disk ext4 metadata that ext4_rs does not expose, such as
+//! the inode body beyond `i_extra_isize` and metadata checksums.
+
+use crate::Disk;
+use ext4_rs::{BlockDevice, BLOCK_SIZE};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+
+pub const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+pub const EXT4_FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+pub const EXT4_FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+pub const EXT4_GOOD_OLD_INODE_SIZE: usize = 128;
+
+// Superblock field offsets.
+const S_FIRST_DATA_BLOCK: usize = 0x14;
+const S_INODES_PER_GROUP: usize = 0x28;
+const S_INODE_SIZE: usize = 0x58;
+const S_FEATURE_INCOMPAT: usize = 0x60;
+const S_FEATURE_RO_COMPAT: usize = 0x64;
+const S_UUID: usize = 0x68;
+const S_DESC_SIZE: usize = 0xFE;
+const S_CHECKSUM_SEED: usize = 0x270;
+
+// Group descriptor field offsets.
+const BG_INODE_TABLE_LO: usize = 0x08;
+const BG_INODE_TABLE_HI: usize = 0x28;
+
+// Inode field offsets.
+pub const I_MODE: usize = 0x00;
+const I_UID: usize = 0x02;
+pub const I_SIZE_LO: usize = 0x04;
+const I_ATIME: usize = 0x08;
+const I_CTIME: usize = 0x0C;
+const I_MTIME: usize = 0x10;
+pub const I_DTIME: usize = 0x14;
+const I_GID: usize = 0x18;
+pub const I_LINKS_COUNT: usize = 0x1A;
+pub const I_BLOCKS_LO: usize = 0x1C;
+pub const I_FLAGS: usize = 0x20;
+pub const I_BLOCK: usize = 0x28;
+pub const I_BLOCK_SIZE: usize = 60;
+pub const I_GENERATION: usize = 0x64;
+pub const I_FILE_ACL_LO: usize = 0x68;
+pub const I_SIZE_HIGH: usize = 0x6C;
+pub const I_FILE_ACL_HIGH: usize = 0x76;
+const I_UID_HIGH: usize = 0x78;
+const I_GID_HIGH: usize = 0x7A;
+const I_CHECKSUM_LO: usize = 0x7C;
+pub const I_EXTRA_ISIZE: usize = 0x80;
+const I_CHECKSUM_HI: usize = 0x82;
+const I_CTIME_EXTRA: usize = 0x84;
+pub const I_MTIME_EXTRA: usize = 0x88;
+const I_ATIME_EXTRA: usize = 0x8C;
+const I_CRTIME: usize = 0x90;
+const I_CRTIME_EXTRA: usize = 0x94;
+
+pub fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+pub fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+pub fn put16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+pub fn put32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// crc32c without pre- or post-inversion, as used by `ext4_chksum`.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Geometry and checksum parameters read from the superblock.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub inodes_per_group: u32,
+    pub inode_size: usize,
+    pub desc_size: usize,
+    pub first_data_block: u32,
+    pub feature_ro_compat: u32,
+    pub csum_seed: u32,
+}
+
+impl Layout {
+    pub fn load(disk: &Disk) -> Self {
+        let sb = disk.read_offset(SUPERBLOCK_OFFSET);
+        Self::from_superblock(&sb)
+    }
+
+    pub fn from_superblock(sb: &[u8]) -> Self {
+        let feature_incompat = le32(sb, S_FEATURE_INCOMPAT);
+        let desc_size = if feature_incompat & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+            le16(sb, S_DESC_SIZE) as usize
+        } else {
+            32
+        };
+        let csum_seed = if feature_incompat & EXT4_FEATURE_INCOMPAT_CSUM_SEED != 0 {
+            le32(sb, S_CHECKSUM_SEED)
+        } else {
+            crc32c(!0, &sb[S_UUID..S_UUID + 16])
+        };
+        let inode_size = match le16(sb, S_INODE_SIZE) as usize {
+            0 => EXT4_GOOD_OLD_INODE_SIZE,
+            size => size,
+        };
+
+        Self {
+            inodes_per_group: le32(sb, S_INODES_PER_GROUP),
+            inode_size,
+            desc_size,
+            first_data_block: le32(sb, S_FIRST_DATA_BLOCK),
+            feature_ro_compat: le32(sb, S_FEATURE_RO_COMPAT),
+            csum_seed,
+        }
+    }
+
+    pub fn has_metadata_csum(&self) -> bool {
+        self.feature_ro_compat & EXT4_FEATURE_RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    /// Byte offset of inode `ino` in the image.
+    fn inode_offset(&self, disk: &Disk, ino: u32) -> usize {
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as usize;
+
+        let gdt = (self.first_data_block as usize + 1) * BLOCK_SIZE;
+        let desc = disk.read_offset(gdt + group * self.desc_size);
+        let mut table = le32(&desc, BG_INODE_TABLE_LO) as u64;
+        if self.desc_size >= 64 {
+            table |= (le32(&desc, BG_INODE_TABLE_HI) as u64) << 32;
+        }
+
+        table as usize * BLOCK_SIZE + index * self.inode_size
+    }
+
+    /// Reads the full on-disk inode record, including the in-inode xattr area.
+    pub fn read_inode(&self, disk: &Disk, ino: u32) -> Vec<u8> {
+        let mut raw = disk.read_offset(self.inode_offset(disk, ino));
+        raw.truncate(self.inode_size);
+        raw
+    }
+
+    /// Writes back a full inode record, refreshing its checksum.
+    pub fn write_inode(&self, disk: &Disk, ino: u32, raw: &mut [u8]) {
+        if self.has_metadata_csum() {
+            let csum = self.inode_checksum(ino, raw);
+            put16(raw, I_CHECKSUM_LO, csum as u16);
+            if self.has_checksum_hi(raw) {
+                put16(raw, I_CHECKSUM_HI, (csum >> 16) as u16);
+            }
+        }
+        disk.write_offset(self.inode_offset(disk, ino), raw);
+    }
+
+    fn has_checksum_hi(&self, raw: &[u8]) -> bool {
+        self.inode_size > EXT4_GOOD_OLD_INODE_SIZE && le16(raw, I_EXTRA_ISIZE) >= 4
+    }
+
+    /// Per-inode checksum seed, also used for extent tree blocks.
+    pub fn inode_csum_seed(&self, ino: u32, generation: u32) -> u32 {
+        let crc = crc32c(self.csum_seed, &ino.to_le_bytes());
+        crc32c(crc, &generation.to_le_bytes())
+    }
+
+    fn inode_checksum(&self, ino: u32, raw: &[u8]) -> u32 {
+        let mut buf = raw.to_vec();
+        put16(&mut buf, I_CHECKSUM_LO, 0);
+        if self.has_checksum_hi(raw) {
+            put16(&mut buf, I_CHECKSUM_HI, 0);
+        }
+        let seed = self.inode_csum_seed(ino, le32(raw, I_GENERATION));
+        crc32c(seed, &buf)
+    }
+
+    /// Checksum of an external xattr block, with `h_checksum` at `csum_off`.
+    pub fn xattr_block_checksum(&self, block: u64, buf: &[u8], csum_off: usize) -> u32 {
+        let mut data = buf.to_vec();
+        put32(&mut data, csum_off, 0);
+        let crc = crc32c(self.csum_seed, &block.to_le_bytes());
+        crc32c(crc, &data)
+    }
+}
+
+/// Returns the inode's external xattr block, if any.
+pub fn inode_file_acl(raw: &[u8]) -> u64 {
+    le32(raw, I_FILE_ACL_LO) as u64 | (le16(raw, I_FILE_ACL_HIGH) as u64) << 32
+}
+
+pub fn set_inode_file_acl(raw: &mut [u8], block: u64) {
+    put32(raw, I_FILE_ACL_LO, block as u32);
+    put16(raw, I_FILE_ACL_HIGH, (block >> 32) as u16);
+}
+
+/// Fills a zeroed raw inode for a new inode of `mode`, owned by `uid` and
+/// `gid`, with room for the extra timestamp fields if the record has it.
+pub fn init_inode(raw: &mut [u8], mode: u32, uid: u32, gid: u32, links: u16, generation: u32) {
+    put16(raw, I_MODE, mode as u16);
+    put16(raw, I_UID, uid as u16);
+    put16(raw, I_UID_HIGH, (uid >> 16) as u16);
+    put16(raw, I_GID, gid as u16);
+    put16(raw, I_GID_HIGH, (gid >> 16) as u16);
+    put16(raw, I_LINKS_COUNT, links);
+    put32(raw, I_GENERATION, generation);
+    if raw.len() > EXT4_GOOD_OLD_INODE_SIZE {
+        put16(raw, I_EXTRA_ISIZE, 32);
+    }
+}
+
+pub fn inode_size(raw: &[u8]) -> u64 {
+    le32(raw, I_SIZE_LO) as u64 | (le32(raw, I_SIZE_HIGH) as u64) << 32
+}
+
+pub fn set_inode_size(raw: &mut [u8], size: u64) {
+    put32(raw, I_SIZE_LO, size as u32);
+    put32(raw, I_SIZE_HIGH, (size >> 32) as u32);
+}
+
+/// Device number of a character or block device inode, in the encoding
+/// FUSE uses (the kernel's `new_encode_dev`). ext4 keeps small numbers in
+/// the old 16-bit format in `i_block[0]` and others in `i_block[1]`.
+pub fn inode_rdev(raw: &[u8]) -> u32 {
+    let old = le32(raw, I_BLOCK);
+    let (major, minor) = if old != 0 {
+        ((old >> 8) & 0xff, old & 0xff)
+    } else {
+        let new = le32(raw, I_BLOCK + 4);
+        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+    };
+    encode_dev(major, minor)
+}
+
+/// Stores `rdev`, as FUSE encodes it, the way `ext4_encode_dev` does.
+pub fn set_inode_rdev(raw: &mut [u8], rdev: u32) {
+    let major = (rdev & 0xfff00) >> 8;
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xfff00);
+    raw[I_BLOCK..I_BLOCK + I_BLOCK_SIZE].fill(0);
+    if major < 256 && minor < 256 {
+        put32(raw, I_BLOCK, (major << 8) | minor);
+    } else {
+        put32(raw, I_BLOCK + 4, encode_dev(major, minor));
+    }
+}
+
+fn encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// The four inode timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeTime {
+    Access,
+    Modify,
+    Change,
+    Create,
+}
+
+impl InodeTime {
+    /// Offsets of the seconds field and of its `*_extra` field. crtime has
+    /// no seconds field in the 128-byte inode.
+    fn offsets(self) -> (usize, usize) {
+        match self {
+            InodeTime::Access => (I_ATIME, I_ATIME_EXTRA),
+            InodeTime::Modify => (I_MTIME, I_MTIME_EXTRA),
+            InodeTime::Change => (I_CTIME, I_CTIME_EXTRA),
+            InodeTime::Create => (I_CRTIME, I_CRTIME_EXTRA),
+        }
+    }
+}
+
+/// Whether the inode body reaches past `off`, so the field there exists.
+fn has_extra_field(raw: &[u8], off: usize) -> bool {
+    raw.len() >= off + 4
+        && raw.len() > EXT4_GOOD_OLD_INODE_SIZE
+        && EXT4_GOOD_OLD_INODE_SIZE + le16(raw, I_EXTRA_ISIZE) as usize >= off + 4
+}
+
+/// Reads a timestamp. The seconds field is signed; its `*_extra` field, when
+/// the inode has one, holds two more epoch bits, which carry dates up to 2446,
+/// and the nanoseconds.
+pub fn inode_time(raw: &[u8], which: InodeTime) -> SystemTime {
+    let (base, extra) = which.offsets();
+    if which == InodeTime::Create && !has_extra_field(raw, base) {
+        return UNIX_EPOCH;
+    }
+    let mut secs = le32(raw, base) as i32 as i64;
+    let mut nsec = 0;
+    if has_extra_field(raw, extra) {
+        let extra = le32(raw, extra);
+        secs += ((extra & 3) as i64) << 32;
+        nsec = (extra >> 2).min(999_999_999);
+    }
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsec)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::from_nanos(nsec as u64)
+    }
+}
+
+/// Stores a timestamp the way `ext4_encode_extra_time` does. Without an
+/// extra field only whole seconds from 1901 to 2038 can be kept.
+pub fn set_inode_time(raw: &mut [u8], which: InodeTime, time: SystemTime) {
+    let (base, extra) = which.offsets();
+    if which == InodeTime::Create && !has_extra_field(raw, base) {
+        return;
+    }
+    let (secs, nsec) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    if has_extra_field(raw, extra) {
+        // Epoch bits 0b11 on a negative time would read back 2^34 late, so
+        // the representable range is [-2^31, 2^34 - 2^31).
+        let secs = secs.clamp(-(1 << 31), (1 << 34) - (1 << 31) - 1);
+        put32(raw, base, secs as u32);
+        let epoch = ((secs - secs as i32 as i64) >> 32) as u32 & 3;
+        put32(raw, extra, epoch | nsec << 2);
+    } else {
+        let secs = secs.clamp(i32::MIN as i64, i32::MAX as i64);
+        put32(raw, base, secs as u32);
+    }
+}
@@ -0,0 +1,52 @@
+//! The ext4 orphan list: inodes whose last link is gone while they are
+//! still in use, or that are being truncated. The superblock's
+//! `s_last_orphan` holds the first one and each orphan's `i_dtime` the
+//! next, as the kernel keeps them, so either can clean up after the other.
+
+use crate::ondisk::{le32, put32, Layout, I_DTIME, SUPERBLOCK_OFFSET};
+use crate::superblock::{S_INODES_COUNT, S_LAST_ORPHAN};
+use crate::{Disk, EINVAL, ENOENT};
+use ext4_rs::BlockDevice;
+
+/// The orphans in list order, stopping at an inode number that cannot be
+/// valid or that was already seen.
+pub fn list(disk: &Disk, layout: &Layout) -> Vec<u32> {
+    let sb = disk.read_offset(SUPERBLOCK_OFFSET);
+    let inodes_count = le32(&sb, S_INODES_COUNT);
+    let mut orphans = Vec::new();
+    let mut ino = le32(&sb, S_LAST_ORPHAN);
+    while ino != 0 && ino <= inodes_count && !orphans.contains(&ino) {
+        orphans.push(ino);
+        ino = le32(&layout.read_inode(disk, ino), I_DTIME);
+    }
+    orphans
+}
+
+/// Puts `ino` at the head of the list.
+pub fn add(disk: &Disk, layout: &Layout, ino: u32) {
+    let head = le32(&disk.read_offset(SUPERBLOCK_OFFSET), S_LAST_ORPHAN);
+    set_next(disk, layout, ino, head);
+    disk.set_superblock_field(S_LAST_ORPHAN, &ino.to_le_bytes());
+}
+
+/// Takes `ino` off the list; fails with ENOENT if it is not on it.
+pub fn remove(disk: &Disk, layout: &Layout, ino: u32) -> Result<(), i32> {
+    if ino == 0 {
+        return Err(EINVAL);
+    }
+    let orphans = list(disk, layout);
+    let pos = orphans.iter().position(|&o| o == ino).ok_or(ENOENT)?;
+    let next = orphans.get(pos + 1).copied().unwrap_or(0);
+    match pos {
+        0 => disk.set_superblock_field(S_LAST_ORPHAN, &next.to_le_bytes()),
+        _ => set_next(disk, layout, orphans[pos - 1], next),
+    }
+    set_next(disk, layout, ino, 0);
+    Ok(())
+}
+
+fn set_next(disk: &Disk, layout: &Layout, ino: u32, next: u32) {
+    let mut raw = layout.read_inode(disk, ino);
+    put32(&mut raw, I_DTIME, next);
+    layout.write_inode(disk, ino, &mut raw);
+}
@@ -0,0 +1,412 @@
+//! Serves FUSE requests on a pool of worker threads. fuser calls into the
+//! session thread one request at a time; each request is handed to a
+//! worker together with its reply, so a slow operation does not hold up
+//! the others. `Ext4Fuse` orders the workers with its inode locks.
+
+use crate::credentials::Caller;
+use crate::pool::WorkerPool;
+use crate::Ext4Fuse;
+use fuser::{
+    fuse_forget_one, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use std::ffi::{c_int, OsStr};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub struct Server {
+    fs: Arc<Ext4Fuse>,
+    pool: WorkerPool,
+}
+
+impl Server {
+    pub fn new(fs: Ext4Fuse, threads: usize) -> Self {
+        Self {
+            fs: Arc::new(fs),
+            pool: WorkerPool::new(threads),
+        }
+    }
+
+    pub fn fs(&self) -> Arc<Ext4Fuse> {
+        self.fs.clone()
+    }
+
+    fn spawn(&self, job: impl FnOnce(&Ext4Fuse) + Send + 'static) {
+        let fs = self.fs.clone();
+        self.pool.execute(move || job(&fs));
+    }
+}
+
+impl Filesystem for Server {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.fs.init(&Caller::from(req), config)
+    }
+
+    /// Lets the requests still queued finish before the session ends.
+    fn destroy(&mut self) {
+        self.pool.join();
+        self.fs.destroy();
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.lookup(&req, parent, &name, reply));
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.forget(&req, ino, nlookup));
+    }
+
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        let req = Caller::from(req);
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|node| (node.nodeid, node.nlookup))
+            .collect();
+        self.spawn(move |fs| fs.batch_forget(&req, &nodes));
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.getattr(&req, ino, fh, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| {
+            fs.setattr(
+                &req, inode, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime,
+                bkuptime, flags, reply,
+            )
+        });
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.read(&req, ino, fh, offset, size, flags, lock, reply));
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.readdir(&req, ino, fh, offset, reply));
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.readdirplus(&req, ino, fh, offset, reply));
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let (req, data) = (Caller::from(req), data.to_vec());
+        self.spawn(move |fs| {
+            fs.write(
+                &req,
+                ino,
+                fh,
+                offset,
+                &data,
+                write_flags,
+                flags,
+                lock_owner,
+                reply,
+            )
+        });
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.unlink(&req, parent, &name, reply));
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.mknod(&req, parent, &name, mode, umask, rdev, reply));
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.mkdir(&req, parent, &name, mode, umask, reply));
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.rmdir(&req, parent, &name, reply));
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (req, name, newname) = (Caller::from(req), name.to_owned(), newname.to_owned());
+        self.spawn(move |fs| fs.rename(&req, parent, &name, newparent, &newname, flags, reply));
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.open(&req, ino, flags, reply));
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.opendir(&req, ino, flags, reply));
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.releasedir(&req, ino, fh, flags, reply));
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.flush(&req, ino, fh, lock_owner, reply));
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.release(&req, ino, fh, flags, lock_owner, flush, reply));
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.fsync(&req, ino, fh, datasync, reply));
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.fsyncdir(&req, ino, fh, datasync, reply));
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.fallocate(&req, ino, fh, offset, length, mode, reply));
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| {
+            fs.copy_file_range(
+                &req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+            )
+        });
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let (req, in_data) = (Caller::from(req), in_data.to_vec());
+        self.spawn(move |fs| fs.ioctl(&req, ino, fh, flags, cmd, &in_data, out_size, reply));
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.lseek(&req, ino, fh, offset, whence, reply));
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.getlk(&req, ino, fh, lock_owner, start, end, typ, pid, reply));
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| {
+            fs.setlk(
+                &req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply,
+            )
+        });
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.access(&req, ino, mask, reply));
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (req, name, value) = (Caller::from(req), name.to_owned(), value.to_vec());
+        self.spawn(move |fs| fs.setxattr(&req, ino, &name, &value, flags, position, reply));
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.getxattr(&req, ino, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let req = Caller::from(req);
+        self.spawn(move |fs| fs.listxattr(&req, ino, size, reply));
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (Caller::from(req), name.to_owned());
+        self.spawn(move |fs| fs.removexattr(&req, ino, &name, reply));
+    }
+}
@@ -0,0 +1,137 @@
+//! Superblock fields ext4libtest maintains itself. ext4_rs writes the
+//! superblock back from the copy it read at mount, so these fields are
+//! kept here and patched into every superblock write that reaches the disk.
+
+use crate::ondisk::{
+    crc32c, le16, le32, put32, EXT4_FEATURE_RO_COMPAT_METADATA_CSUM, SUPERBLOCK_OFFSET,
+};
+use crate::Disk;
+use ext4_rs::BlockDevice;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SUPERBLOCK_SIZE: usize = 1024;
+
+pub const S_INODES_COUNT: usize = 0x00;
+pub const S_LAST_ORPHAN: usize = 0xE8;
+
+const S_MTIME: usize = 0x2C;
+const S_WTIME: usize = 0x30;
+pub const S_MNT_COUNT: usize = 0x34;
+const S_MAX_MNT_COUNT: usize = 0x36;
+pub const S_STATE: usize = 0x3A;
+const S_FEATURE_RO_COMPAT: usize = 0x64;
+pub const S_LAST_MOUNTED: usize = 0x88;
+const S_WTIME_HI: usize = 0x274;
+const S_MTIME_HI: usize = 0x275;
+const S_CHECKSUM: usize = 0x3FC;
+
+pub const S_LAST_MOUNTED_LEN: usize = 64;
+
+/// `s_state` bits.
+pub const EXT4_VALID_FS: u16 = 0x0001;
+const EXT4_ERROR_FS: u16 = 0x0002;
+
+#[derive(Debug, Default)]
+pub struct SuperblockFields {
+    /// Field offset in the superblock to its value.
+    fields: Mutex<BTreeMap<usize, Vec<u8>>>,
+}
+
+impl SuperblockFields {
+    pub fn set(&self, offset: usize, value: &[u8]) {
+        self.fields.lock().unwrap().insert(offset, value.to_vec());
+    }
+
+    /// Applies the fields to a write of `data` at byte `offset` of the
+    /// image, refreshing the checksum when the whole superblock is written.
+    pub fn patch<'a>(&self, offset: usize, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let end = offset + data.len();
+        let fields = self.fields.lock().unwrap();
+        if fields.is_empty()
+            || end <= SUPERBLOCK_OFFSET
+            || offset >= SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE
+        {
+            return Cow::Borrowed(data);
+        }
+
+        let mut data = data.to_vec();
+        for (&field, value) in fields.iter() {
+            let at = SUPERBLOCK_OFFSET + field;
+            if at >= offset && at + value.len() <= end {
+                data[at - offset..at - offset + value.len()].copy_from_slice(value);
+            }
+        }
+        if offset <= SUPERBLOCK_OFFSET && end >= SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE {
+            let sb = SUPERBLOCK_OFFSET - offset;
+            set_checksum(&mut data[sb..sb + SUPERBLOCK_SIZE]);
+        }
+        Cow::Owned(data)
+    }
+}
+
+/// Stores `s_checksum` of a metadata_csum filesystem.
+pub fn set_checksum(sb: &mut [u8]) {
+    if le32(sb, S_FEATURE_RO_COMPAT) & EXT4_FEATURE_RO_COMPAT_METADATA_CSUM != 0 {
+        let csum = crc32c(!0, &sb[..S_CHECKSUM]);
+        put32(sb, S_CHECKSUM, csum);
+    }
+}
+
+/// Records a mount at `mountpoint` as the kernel does: the filesystem is
+/// not clean until unmounted, and the mount counts towards the next
+/// e2fsck. Returns `s_state` as it was, for `mark_unmounted`.
+pub fn mark_mounted(disk: &Disk, mountpoint: &str) -> u16 {
+    let sb = disk.read_offset(SUPERBLOCK_OFFSET);
+    let state = le16(&sb, S_STATE);
+    let mnt_count = le16(&sb, S_MNT_COUNT);
+    let max_mnt_count = le16(&sb, S_MAX_MNT_COUNT) as i16;
+
+    if state & EXT4_VALID_FS == 0 {
+        log::warn!(
+            "mounting a filesystem that was not cleanly unmounted, running e2fsck is recommended"
+        );
+    } else if state & EXT4_ERROR_FS != 0 {
+        log::warn!("mounting a filesystem with errors, running e2fsck is recommended");
+    }
+    if max_mnt_count > 0 && mnt_count >= max_mnt_count as u16 {
+        log::warn!(
+            "maximal mount count reached ({} of {}), running e2fsck is recommended",
+            mnt_count,
+            max_mnt_count
+        );
+    }
+
+    let mut last_mounted = [0u8; S_LAST_MOUNTED_LEN];
+    let path = mountpoint.as_bytes();
+    let len = path.len().min(S_LAST_MOUNTED_LEN - 1);
+    last_mounted[..len].copy_from_slice(&path[..len]);
+
+    disk.superblock
+        .set(S_STATE, &(state & !EXT4_VALID_FS).to_le_bytes());
+    disk.superblock
+        .set(S_MNT_COUNT, &mnt_count.wrapping_add(1).to_le_bytes());
+    set_time(disk, S_MTIME, S_MTIME_HI);
+    disk.superblock.set(S_LAST_MOUNTED, &last_mounted);
+    disk.write_superblock();
+    state
+}
+
+/// Puts back the state the filesystem was mounted with, which is clean
+/// unless e2fsck is still due.
+pub fn mark_unmounted(disk: &Disk, state: u16) {
+    disk.superblock.set(S_STATE, &state.to_le_bytes());
+    set_time(disk, S_WTIME, S_WTIME_HI);
+    disk.write_superblock();
+}
+
+/// Sets a superblock timestamp to now, with its high byte.
+fn set_time(disk: &Disk, lo: usize, hi: usize) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    disk.superblock.set(lo, &(secs as u32).to_le_bytes());
+    disk.superblock.set(hi, &[(secs >> 32) as u8]);
+}
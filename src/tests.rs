@@ -1,8 +1,25 @@
 use super::*;
 
+/// Tests share ex4.img, so those that use it run one at a time.
+fn lock_image() -> std::sync::MutexGuard<'static, ()> {
+    static IMAGE: Mutex<()> = Mutex::new(());
+    IMAGE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs its closure when dropped, to undo a test's changes to the image
+/// even when an assertion fails.
+struct Cleanup<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for Cleanup<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
 #[test]
 fn test_open() {
-    let disk = Arc::new(Disk {});
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
     let ext4 = Ext4::open(disk);
 
     let path = ".";
@@ -27,7 +44,8 @@ fn test_open() {
 
 #[test]
 fn test_file_write_and_read_random_data() {
-    let disk = Arc::new(Disk {});
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
     let ext4 = Ext4::open(disk);
 
     use rand::Rng;
@@ -78,3 +96,1118 @@ fn test_file_write_and_read_random_data() {
     }
 }
 
+#[test]
+fn test_xattr_round_trip() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let ext4 = Ext4::open(disk.clone());
+    let layout = Layout::load(&disk);
+    let xattrs = XattrStore {
+        ext4: &ext4,
+        disk: &disk,
+        layout: &layout,
+    };
+
+    let inode_num = ext4.ext4_file_open("xattr_test_file", "w+").unwrap() as u32;
+
+    // VFS_CAP_REVISION_2 with cap_net_bind_service permitted.
+    let mut capability = vec![0u8; 20];
+    capability[..4].copy_from_slice(&0x0200_0001u32.to_le_bytes());
+    capability[4] = 1 << 2;
+    // Too large for the inode body, so it spills into the xattr block.
+    let blob = vec![0x5a; 1024];
+
+    let r = xattrs.set(inode_num, xattr::XATTR_INDEX_SECURITY, b"capability", Some(&capability[..]), 0);
+    assert!(r.is_ok(), "set security.capability error {:?}", r.err());
+    let r = xattrs.set(inode_num, xattr::XATTR_INDEX_USER, b"build.tag", Some(&b"release"[..]), xattr::XATTR_CREATE);
+    assert!(r.is_ok(), "set user.build.tag error {:?}", r.err());
+    let r = xattrs.set(inode_num, xattr::XATTR_INDEX_USER, b"blob", Some(&blob[..]), 0);
+    assert!(r.is_ok(), "set user.blob error {:?}", r.err());
+
+    let r = xattrs.set(inode_num, xattr::XATTR_INDEX_USER, b"build.tag", Some(&b"debug"[..]), xattr::XATTR_CREATE);
+    assert_eq!(r, Err(EEXIST));
+    let r = xattrs.set(inode_num, xattr::XATTR_INDEX_USER, b"missing", Some(&b"x"[..]), xattr::XATTR_REPLACE);
+    assert_eq!(r, Err(ENODATA));
+
+    assert_eq!(
+        xattrs.get(inode_num, xattr::XATTR_INDEX_SECURITY, b"capability"),
+        Ok(Some(capability))
+    );
+    assert_eq!(
+        xattrs.get(inode_num, xattr::XATTR_INDEX_USER, b"build.tag"),
+        Ok(Some(b"release".to_vec()))
+    );
+    assert_eq!(xattrs.get(inode_num, xattr::XATTR_INDEX_USER, b"blob"), Ok(Some(blob)));
+
+    for name in [&b"build.tag"[..], b"blob"] {
+        assert_eq!(xattrs.set(inode_num, xattr::XATTR_INDEX_USER, name, None, 0), Ok(()));
+    }
+    assert_eq!(xattrs.set(inode_num, xattr::XATTR_INDEX_SECURITY, b"capability", None, 0), Ok(()));
+    assert_eq!(xattrs.get(inode_num, xattr::XATTR_INDEX_USER, b"blob"), Ok(None));
+}
+
+#[test]
+fn test_xattr_in_ea_inode_is_kept() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs.ext4.fuse_mknod_with_attr(2, "ea_inode_test", S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num;
+    let before = fs.layout.read_inode(&fs.disk, ino);
+    let _cleanup = Cleanup(|| {
+        fs.layout.write_inode(&fs.disk, ino, &mut before.clone());
+        let _ = fs.ext4.fuse_unlink(2, "ea_inode_test");
+    });
+
+    // "user.big" in the inode body, its megabyte value in inode 99.
+    let mut raw = before.clone();
+    let body = ondisk::EXT4_GOOD_OLD_INODE_SIZE + ondisk::le16(&raw, ondisk::I_EXTRA_ISIZE) as usize;
+    ondisk::put32(&mut raw, body, xattr::XATTR_MAGIC);
+    let entry = body + 4;
+    raw[entry] = 3;
+    raw[entry + 1] = xattr::XATTR_INDEX_USER;
+    ondisk::put32(&mut raw, entry + 4, 99);
+    ondisk::put32(&mut raw, entry + 8, 1 << 20);
+    raw[entry + 16..entry + 19].copy_from_slice(b"big");
+    fs.layout.write_inode(&fs.disk, ino, &mut raw);
+    let written = fs.layout.read_inode(&fs.disk, ino);
+
+    let xattrs = fs.xattrs();
+    assert_eq!(xattrs.list(ino), Ok(vec![]));
+    assert_eq!(xattrs.get(ino, xattr::XATTR_INDEX_USER, b"big"), Ok(None));
+    // Rewriting the entries would lose the one in the EA inode.
+    assert_eq!(xattrs.set(ino, xattr::XATTR_INDEX_USER, b"other", Some(&b"x"[..]), 0), Err(EOPNOTSUPP));
+    assert_eq!(xattrs.set(ino, xattr::XATTR_INDEX_USER, b"big", None, 0), Err(EOPNOTSUPP));
+    assert_eq!(xattrs.clear(ino), Err(EOPNOTSUPP));
+    assert_eq!(fs.layout.read_inode(&fs.disk, ino)[body..], written[body..]);
+}
+
+#[test]
+fn test_acl_conversion_and_mask() {
+    use acl::*;
+
+    // user::rw- user:1000:rwx group::r-x mask::r-- other::---
+    let entries = [
+        (ACL_USER_OBJ, 6u16, u32::MAX),
+        (ACL_USER, 7, 1000),
+        (ACL_GROUP_OBJ, 5, u32::MAX),
+        (ACL_MASK, 4, u32::MAX),
+        (ACL_OTHER, 0, u32::MAX),
+    ];
+    let mut xattr_value = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        xattr_value.extend_from_slice(&tag.to_le_bytes());
+        xattr_value.extend_from_slice(&perm.to_le_bytes());
+        xattr_value.extend_from_slice(&id.to_le_bytes());
+    }
+
+    let acl = Acl::from_xattr(&xattr_value).unwrap();
+    let on_disk = acl.to_disk();
+    // Four short entries, one long entry and the header.
+    assert_eq!(on_disk.len(), 4 + 4 * 4 + 8);
+    assert_eq!(Acl::from_disk(&on_disk).unwrap(), acl);
+    assert_eq!(acl.to_xattr(), xattr_value);
+    assert_eq!(acl.equiv_mode(), (0o640, false));
+
+    let in_group = |gid| gid == 100;
+    // The named user is limited by the mask, the owner is not.
+    assert!(acl.permits(1000, in_group, 0, 100, ACL_READ));
+    assert!(!acl.permits(1000, in_group, 0, 100, ACL_WRITE));
+    assert!(acl.permits(0, in_group, 0, 100, ACL_WRITE));
+    assert!(!acl.permits(2000, in_group, 0, 100, ACL_EXECUTE));
+    assert!(!acl.permits(2000, |_| false, 0, 100, ACL_READ));
+
+    let mut inherited = acl.clone();
+    let mut mode = 0o100755;
+    assert!(inherited.create_masq(&mut mode));
+    assert_eq!(mode, 0o100640);
+
+    // Missing mask with a named user is invalid.
+    let invalid: Vec<u8> = xattr_value[..4 + 8 * 3]
+        .iter()
+        .chain(&xattr_value[4 + 8 * 4..])
+        .copied()
+        .collect();
+    assert_eq!(Acl::from_xattr(&invalid), Err(EINVAL));
+}
+
+#[test]
+fn test_extent_tree_punch_and_collapse() {
+    use extent::{Extent, ExtentTree};
+
+    let mut tree = ExtentTree::default();
+    tree.insert(Extent { lblock: 0, len: 8, pblock: 1000, unwritten: false });
+    tree.insert(Extent { lblock: 16, len: 4, pblock: 2000, unwritten: true });
+
+    // Punching blocks 2..4 splits the first extent around the hole.
+    let freed = tree.remove(2, 4);
+    assert_eq!(freed, vec![Extent { lblock: 2, len: 2, pblock: 1002, unwritten: false }]);
+    assert_eq!(tree.holes(0, 20), vec![(2, 4), (8, 16)]);
+    assert_eq!(tree.goal(16), Some(1008));
+
+    // Collapsing the gap 8..16 pulls the unwritten extent down.
+    tree.remove(8, 16);
+    tree.shift(16, -8);
+    assert_eq!(tree.holes(0, 12), vec![(2, 4)]);
+    assert!(tree.overlapping(8, 12).all(|ext| ext.unwritten && ext.pblock == 2000));
+
+    tree.set_unwritten(9, 10, false);
+    let written: Vec<u32> = tree
+        .overlapping(8, 12)
+        .filter(|ext| !ext.unwritten)
+        .map(|ext| ext.lblock)
+        .collect();
+    assert_eq!(written, vec![9]);
+}
+
+#[test]
+fn test_extent_tree_seek_data_and_hole() {
+    use extent::{Extent, ExtentTree};
+
+    // data 0..4, unwritten 4..6, hole 6..10, data 10..12
+    let mut tree = ExtentTree::default();
+    tree.insert(Extent { lblock: 0, len: 4, pblock: 500, unwritten: false });
+    tree.insert(Extent { lblock: 4, len: 2, pblock: 504, unwritten: true });
+    tree.insert(Extent { lblock: 10, len: 2, pblock: 900, unwritten: false });
+
+    assert_eq!(tree.next_data(0), Some(0));
+    assert_eq!(tree.next_data(2), Some(2));
+    assert_eq!(tree.next_data(4), Some(10));
+    assert_eq!(tree.next_data(12), None);
+
+    assert_eq!(tree.next_hole(0), 4);
+    assert_eq!(tree.next_hole(5), 5);
+    assert_eq!(tree.next_hole(10), 12);
+}
+
+#[test]
+fn test_fallocate_modes() {
+    let _image = lock_image();
+    use fallocate::*;
+
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs.ext4.fuse_mknod_with_attr(2, "fallocate_test", S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num;
+    let _cleanup = Cleanup(|| {
+        let _ = fs.truncate_blocks(ino, 0);
+        let _ = fs.ext4.fuse_unlink(2, "fallocate_test");
+    });
+    let store = fs.extents();
+    let bs = BLOCK_SIZE as u64;
+
+    assert_eq!(fallocate(&store, ino, 0, 300 * bs, FALLOC_FL_KEEP_SIZE), Ok(()));
+    assert_eq!(store.size(ino), 0);
+    let tree = store.load(ino).unwrap();
+    assert_eq!(tree.extents.iter().map(|ext| ext.len).sum::<u32>(), 300);
+    assert!(tree.extents.iter().all(|ext| ext.unwritten));
+
+    assert_eq!(fallocate(&store, ino, 0, bs, FALLOC_FL_PUNCH_HOLE), Err(EOPNOTSUPP));
+    assert_eq!(fallocate(&store, ino, 0, bs, FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_KEEP_SIZE), Err(EINVAL));
+    // Nothing past the block holding EOF is punched.
+    assert_eq!(fallocate(&store, ino, 0, 300 * bs, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE), Ok(()));
+    assert_eq!(store.load(ino).unwrap().extents.iter().map(|ext| ext.len).sum::<u32>(), 300);
+
+    // Enough extents for an index level; a range lookup finds what a full
+    // load does.
+    fallocate(&store, ino, 0, 300 * bs, 0).unwrap();
+    for i in 0..20 {
+        fallocate(&store, ino, (2 * i + 1) * bs, bs, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+    }
+    let tree = store.load(ino).unwrap();
+    assert!(!tree.nodes.is_empty());
+    for (first, last) in [(0, 1), (3, 4), (5, 17), (38, 300), (300, 400)] {
+        let want: Vec<_> = tree.overlapping(first, last).copied().collect();
+        assert_eq!(store.find(ino, first, last).unwrap(), want, "blocks {}..{}", first, last);
+    }
+
+    let map = fs.extent_map(Path::new("/fallocate_test")).unwrap();
+    let lines: Vec<&str> = map.lines().collect();
+    assert_eq!(lines.len(), tree.extents.len() + 2);
+    let (first, last) = (tree.extents[0], tree.extents[tree.extents.len() - 1]);
+    assert!(lines[1].starts_with(&format!("   0:        0..       0: {:>10}..", first.pblock)));
+    assert!(lines[1].ends_with(":      1: unwritten"));
+    assert!(lines[lines.len() - 2].ends_with(&format!("{:>6}: unwritten,last", last.len)));
+    assert_eq!(lines[lines.len() - 1], format!("{} extents, {} tree blocks", tree.extents.len(), tree.nodes.len()));
+    assert_eq!(fs.extent_map(Path::new("missing")), Err(ENOENT));
+}
+
+#[test]
+fn test_copy_file_range_stops_short_on_enospc() {
+    let _image = lock_image();
+    use fallocate::*;
+
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let store = fs.extents();
+    let bs = BLOCK_SIZE as u64;
+    let create = |name| fs.ext4.fuse_mknod_with_attr(2, name, S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num;
+    let (src, dst, spare, filler) = (create("copy_src"), create("copy_dst"), create("copy_spare"), create("copy_filler"));
+    let _cleanup = Cleanup(|| {
+        let _ = fallocate(&store, filler, 0, store.size(filler).max(1), FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE);
+        for name in ["copy_src", "copy_dst", "copy_spare", "copy_filler"] {
+            let _ = fs.ext4.fuse_unlink(2, name);
+        }
+    });
+
+    // Eight one-block extents with holes between them.
+    for i in 0..16 {
+        fs.ext4.ext4_file_write(src as u64, (i * bs) as i64, &vec![i as u8 + 1; BLOCK_SIZE]).unwrap();
+    }
+    for i in 0..8 {
+        fallocate(&store, src, (2 * i + 1) * bs, bs, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+    }
+
+    // Fill the image up, then give back three blocks.
+    fallocate(&store, spare, 0, 3 * bs, 0).unwrap();
+    let (mut blocks, mut chunk) = (0, 1 << 20);
+    while chunk > 0 {
+        match fallocate(&store, filler, blocks * bs, chunk * bs, 0) {
+            Ok(()) => blocks += chunk,
+            Err(ENOSPC) => chunk /= 2,
+            Err(e) => panic!("filling the image failed: {}", e),
+        }
+    }
+    fallocate(&store, spare, 0, 3 * bs, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+
+    // Three source blocks fit, along with the holes before the fourth.
+    let len = 16 * bs;
+    assert_eq!(copy::copy_file_range(&store, src, 0, dst, 0, len), Ok(6 * bs));
+    assert_eq!(store.size(dst), 6 * bs);
+    let copied = fs.ext4.ext4_file_read(dst as u64, (6 * bs) as u32, 0).unwrap();
+    for (i, block) in copied.chunks(BLOCK_SIZE).enumerate() {
+        let want = if i % 2 == 0 { i as u8 + 1 } else { 0 };
+        assert!(block.iter().all(|&b| b == want), "block {} of the copy", i);
+    }
+
+    // With nothing copied the error itself is reported, and the
+    // destination is left as it was.
+    assert_eq!(copy::copy_file_range(&store, src, 0, dst, 8 * bs, len), Err(ENOSPC));
+    assert_eq!(store.size(dst), 6 * bs);
+}
+
+#[test]
+fn test_lock_split_and_merge() {
+    use lock::{Lock, LockManager};
+
+    let lock = |start, end, typ, owner| Lock { start, end, typ, owner, pid: owner as u32 };
+    let mut locks = LockManager::default();
+
+    // Owner 1 write-locks 0..=99, then unlocks the middle, splitting it.
+    assert_eq!(locks.set(7, lock(0, 99, F_WRLCK, 1)), Ok(()));
+    assert_eq!(locks.set(7, lock(40, 59, F_UNLCK, 1)), Ok(()));
+    assert_eq!(locks.conflict(7, &lock(40, 59, F_WRLCK, 2)), None);
+    assert_eq!(
+        locks.conflict(7, &lock(50, 69, F_RDLCK, 2)),
+        Some(lock(60, 99, F_WRLCK, 1))
+    );
+    assert_eq!(locks.set(7, lock(0, 10, F_RDLCK, 2)), Err(EAGAIN));
+
+    // Shared locks coexist; re-locking the gap merges owner 1's ranges.
+    assert_eq!(locks.set(7, lock(45, 49, F_RDLCK, 2)), Ok(()));
+    assert_eq!(locks.set(7, lock(40, 59, F_WRLCK, 1)), Err(EAGAIN));
+    assert_eq!(locks.set(7, lock(40, 59, F_RDLCK, 1)), Ok(()));
+    locks.release_owner(7, 2);
+    assert_eq!(locks.set(7, lock(40, 59, F_WRLCK, 1)), Ok(()));
+    assert_eq!(
+        locks.conflict(7, &lock(0, u64::MAX, F_RDLCK, 3)),
+        Some(lock(0, 99, F_WRLCK, 1))
+    );
+
+    locks.release_owner(7, 1);
+    assert_eq!(locks.conflict(7, &lock(0, u64::MAX, F_WRLCK, 3)), None);
+}
+
+#[test]
+fn test_inode_flags_setflags_rules() {
+    use iflags::*;
+
+    const EXTENTS: u32 = 0x80000;
+    let old = EXTENTS | EXT4_NODUMP_FL;
+
+    // chattr -d +A by the owner keeps the extents flag.
+    assert_eq!(apply(old, EXTENTS | EXT4_NOATIME_FL, false), Ok(EXTENTS | EXT4_NOATIME_FL));
+    // +i and +a need privilege; +c is not supported.
+    assert_eq!(apply(old, old | EXT4_IMMUTABLE_FL, false), Err(EPERM));
+    assert_eq!(apply(old, old | EXT4_APPEND_FL, true), Ok(old | EXT4_APPEND_FL));
+    assert_eq!(apply(old, old | 0x4, true), Err(EOPNOTSUPP));
+
+    // An immutable inode changes nothing else until -i.
+    let immutable = old | EXT4_IMMUTABLE_FL;
+    assert_eq!(apply(immutable, immutable | EXT4_NOATIME_FL, true), Err(EPERM));
+    assert_eq!(apply(immutable, old, true), Ok(old));
+
+    assert_eq!(from_bsd(old, 0x2), EXTENTS | EXT4_IMMUTABLE_FL);
+}
+
+#[test]
+fn test_rdev_encoding() {
+    let mut raw = vec![0u8; 256];
+    // /dev/sda1 fits the old format, a large minor needs the new one.
+    let sda1 = (8 << 8) | 1;
+    ondisk::set_inode_rdev(&mut raw, sda1);
+    assert_eq!(ondisk::le32(&raw, ondisk::I_BLOCK), 0x0801);
+    assert_eq!(ondisk::inode_rdev(&raw), sda1);
+
+    let nvme = (259 << 8) | (300 & 0xff) | ((300 & !0xff) << 12);
+    ondisk::set_inode_rdev(&mut raw, nvme);
+    assert_eq!(ondisk::le32(&raw, ondisk::I_BLOCK), 0);
+    assert_eq!(ondisk::inode_rdev(&raw), nvme);
+}
+
+#[test]
+fn test_error_paths() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk.clone(), Config::default());
+
+    let top = fs.ext4.fuse_mkdir_with_attr(2, "errno_test", 0o755, 0, 0, 0).unwrap().inode_num as u64;
+    let _cleanup = Cleanup(|| {
+        let _ = fs.ext4.fuse_unlink(top, "file");
+        let _ = fs.ext4.fuse_rmdir(top, "sub");
+        let _ = fs.ext4.fuse_rmdir(2, "errno_test");
+    });
+    let sub = fs.ext4.fuse_mkdir_with_attr(top, "sub", 0o755, 0, 0, 0).unwrap().inode_num as u64;
+    let file = fs.ext4.fuse_mknod_with_attr(top, "file", S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num as u64;
+
+    let missing = fs.ext4.fuse_lookup(top, "missing").unwrap_err();
+    assert_eq!(errno::from_ext4(missing), ENOENT);
+    assert_eq!(fs.check_new_entry(top, OsStr::new("file")), Err(EEXIST));
+    assert_eq!(fs.check_new_entry(top, OsStr::new("missing")), Ok(()));
+
+    let long = OsString::from("x".repeat(dirent::EXT4_NAME_LEN + 1));
+    assert_eq!(fs.check_new_entry(top, &long), Err(ENAMETOOLONG));
+    assert_eq!(fs.check_remove(top, &long, false), Err(ENAMETOOLONG));
+
+    assert_eq!(fs.check_remove(2, OsStr::new("errno_test"), true), Err(ENOTEMPTY));
+    assert_eq!(fs.check_remove(top, OsStr::new("sub"), false), Err(EISDIR));
+    assert_eq!(fs.check_remove(top, OsStr::new("file"), true), Err(ENOTDIR));
+    assert_eq!(fs.check_remove(top, OsStr::new("missing"), false), Err(ENOENT));
+
+    assert_eq!(fs.check_rename(2, OsStr::new("errno_test"), sub, OsStr::new("loop"), 0), Err(EINVAL));
+    assert_eq!(fs.check_rename(top, OsStr::new("sub"), top, OsStr::new("file"), 0), Err(ENOTDIR));
+    assert_eq!(fs.check_rename(top, OsStr::new("file"), top, OsStr::new("sub"), 0), Err(EISDIR));
+    assert_eq!(fs.check_rename(top, OsStr::new("file"), top, OsStr::new("sub"), RENAME_EXCHANGE), Ok(false));
+    assert_eq!(fs.check_rename(top, OsStr::new("file"), top, OsStr::new("file"), 0), Ok(true));
+
+    // An attribute too large for the inode and its xattr block.
+    let req = Caller::default();
+    let value = vec![0u8; BLOCK_SIZE];
+    assert_eq!(fs.xattr_set(&req, file, OsStr::new("user.big"), Some(&value), 0), Err(ENOSPC));
+
+    let config = Config {
+        read_only: true,
+        ..Config::default()
+    };
+    let ro = Ext4Fuse::new(Ext4::open(disk.clone()), disk, config);
+    assert_eq!(ro.check_dir_writable(top), Err(EROFS));
+    assert_eq!(ro.check_writable(file, 0), Err(EROFS));
+    assert_eq!(ro.may_delete(&req, top, OsStr::new("file")), Err(EROFS));
+}
+
+#[test]
+fn test_inode_timestamps_extra_bits() {
+    use ondisk::{inode_time, set_inode_time};
+
+    let mut raw = vec![0u8; 256];
+    ondisk::put16(&mut raw, ondisk::I_EXTRA_ISIZE, 32);
+
+    // 2100-01-01, past the 32-bit signed range, with nanoseconds.
+    let future = UNIX_EPOCH + Duration::new(4_102_444_800, 123_456_789);
+    set_inode_time(&mut raw, InodeTime::Modify, future);
+    assert_eq!(inode_time(&raw, InodeTime::Modify), future);
+    assert_eq!(ondisk::le32(&raw, ondisk::I_MTIME_EXTRA) & 3, 1);
+
+    let past = UNIX_EPOCH - Duration::new(86_400, 0) + Duration::from_nanos(500);
+    set_inode_time(&mut raw, InodeTime::Create, past);
+    assert_eq!(inode_time(&raw, InodeTime::Create), past);
+
+    // A 128-byte inode keeps whole seconds only and has no crtime.
+    let mut small = vec![0u8; 128];
+    set_inode_time(&mut small, InodeTime::Access, future);
+    assert_eq!(inode_time(&small, InodeTime::Access), UNIX_EPOCH + Duration::from_secs(i32::MAX as u64));
+    set_inode_time(&mut small, InodeTime::Create, future);
+    assert_eq!(inode_time(&small, InodeTime::Create), UNIX_EPOCH);
+}
+
+#[test]
+fn test_non_utf8_names() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+
+    // "café" and "naïve" in Latin-1.
+    let name = OsStr::from_bytes(b"caf\xe9");
+    let dir_name = OsStr::from_bytes(b"na\xefve");
+    let req = Caller::default();
+    let ino = fs.make_node(&req, 2, name, S_IFREG | 0o644, 0, 0).unwrap().ino;
+    let dir = fs.make_dir(&req, 2, dir_name, 0o755, 0).unwrap().ino;
+
+    assert_eq!(fs.lookup_name(2, name).map(|attr| attr.ino), Ok(ino));
+    assert!(fs.list_dir(2).unwrap().iter().any(|entry| entry.name == name));
+    assert_eq!(fs.lookup_name(dir, OsStr::new("..")).map(|attr| attr.ino), Ok(2));
+    assert_eq!(fs.links_count(dir as u32), 2);
+    let long = OsString::from_vec(vec![b'x'; 256]);
+    assert_eq!(fs.lookup_name(2, &long).map(|attr| attr.ino), Err(ENAMETOOLONG));
+
+    // Moved into the new directory under another Latin-1 name.
+    let new_name = OsStr::from_bytes(b"\xe9t\xe9");
+    fs.rename_entry(2, name, dir, new_name, 0).unwrap();
+    assert_eq!(fs.lookup_name(2, name).map(|attr| attr.ino), Err(ENOENT));
+    assert_eq!(fs.lookup_name(dir, new_name).map(|attr| attr.ino), Ok(ino));
+    assert_eq!(fs.ext4.fuse_getattr(ino).unwrap().nlink, 1);
+
+    // An indexed directory cannot take the name in just any block.
+    let mut raw = fs.layout.read_inode(&fs.disk, dir as u32);
+    let flags = ondisk::le32(&raw, ondisk::I_FLAGS);
+    ondisk::put32(&mut raw, ondisk::I_FLAGS, flags | 0x1000);
+    fs.layout.write_inode(&fs.disk, dir as u32, &mut raw);
+    assert_eq!(fs.make_node(&req, dir, name, S_IFREG | 0o644, 0, 0).map(|attr| attr.ino), Err(EOPNOTSUPP));
+    ondisk::put32(&mut raw, ondisk::I_FLAGS, flags);
+    fs.layout.write_inode(&fs.disk, dir as u32, &mut raw);
+
+    fs.remove_link(dir, new_name).unwrap();
+    assert_eq!(fs.lookup_name(dir, new_name).map(|attr| attr.ino), Err(ENOENT));
+    assert_eq!(fs.list_dir(dir).unwrap().len(), 2);
+    fs.remove_link(2, dir_name).unwrap();
+    fs.add_dir_link(2, false);
+    assert_eq!(fs.lookup_name(2, dir_name).map(|attr| attr.ino), Err(ENOENT));
+}
+
+#[test]
+fn test_inode_locks_exclusive_waits_for_readers() {
+    use inode_lock::InodeLocks;
+    use std::sync::atomic::AtomicBool;
+
+    let locks = InodeLocks::default();
+    let written = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let first = locks.shared(7);
+        let second = locks.lock(&[7, 8], &[]);
+        let writer = s.spawn(|| {
+            let _guard = locks.exclusive(&[8, 7]);
+            written.store(true, Ordering::SeqCst);
+        });
+        // Another inode is free meanwhile.
+        drop(locks.exclusive(&[9]));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!written.load(Ordering::SeqCst));
+        drop(first);
+        drop(second);
+        writer.join().unwrap();
+    });
+    assert!(written.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_orphan_list_and_lookup_counts() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+
+    let a = fs.ext4.fuse_mknod_with_attr(2, "orphan_a", S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num;
+    let b = fs.ext4.fuse_mknod_with_attr(2, "orphan_b", S_IFREG | 0o644, 0, 0, 0, 0).unwrap().inode_num;
+    let _cleanup = Cleanup(|| {
+        for ino in [a, b] {
+            let _ = orphan::remove(&fs.disk, &fs.layout, ino);
+        }
+        let _ = fs.ext4.fuse_unlink(2, "orphan_b");
+        // Held past its last link, `a` is only freed by hand.
+        if fs.lookup_name(2, OsStr::new("orphan_a")).is_ok() {
+            fs.set_links_count(a, 1);
+            let _ = fs.ext4.fuse_unlink(2, "orphan_a");
+        } else if ondisk::le32(&fs.layout.read_inode(&fs.disk, a), ondisk::I_DTIME) == 0 {
+            let _ = fs.free_inode(a);
+        }
+    });
+    fs.inode_table.lookup(a as u64);
+    fs.inode_table.lookup(a as u64);
+
+    // Only an inode the kernel references is held past its last link.
+    assert_eq!(fs.hold_unlinked(2, OsStr::new("orphan_b")), None);
+    assert_eq!(fs.hold_unlinked(2, OsStr::new("orphan_a")), Some(a));
+    fs.ext4.fuse_unlink(2, "orphan_a").unwrap();
+    fs.orphan(a);
+    assert_eq!(fs.links_count(a), 0);
+    assert_eq!(orphan::list(&fs.disk, &fs.layout).first(), Some(&a));
+
+    orphan::add(&fs.disk, &fs.layout, b);
+    assert!(orphan::list(&fs.disk, &fs.layout).starts_with(&[b, a]));
+    assert_eq!(orphan::remove(&fs.disk, &fs.layout, a), Ok(()));
+    assert_eq!(orphan::remove(&fs.disk, &fs.layout, a), Err(ENOENT));
+    assert_eq!(orphan::remove(&fs.disk, &fs.layout, b), Ok(()));
+    assert!(!orphan::list(&fs.disk, &fs.layout).contains(&b));
+
+    assert!(!fs.inode_table.forget(a as u64, 1));
+    assert!(fs.inode_table.forget(a as u64, 1));
+    assert!(!fs.inode_table.is_referenced(a as u64));
+    fs.free_inode(a).unwrap();
+}
+
+#[test]
+fn test_superblock_mount_bookkeeping() {
+    let _image = lock_image();
+    use superblock::{EXT4_VALID_FS, S_LAST_MOUNTED, S_LAST_MOUNTED_LEN, S_MNT_COUNT, S_STATE};
+
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let sb = disk.read_offset(ondisk::SUPERBLOCK_OFFSET);
+    let (state, mnt_count) = (ondisk::le16(&sb, S_STATE), ondisk::le16(&sb, S_MNT_COUNT));
+    let last_mounted = sb[S_LAST_MOUNTED..][..S_LAST_MOUNTED_LEN].to_vec();
+    // Leave the image as e2fsck saw it, even when an assertion fails.
+    let _cleanup = Cleanup(|| {
+        disk.superblock.set(S_STATE, &state.to_le_bytes());
+        disk.superblock.set(S_MNT_COUNT, &mnt_count.to_le_bytes());
+        disk.superblock.set(S_LAST_MOUNTED, &last_mounted);
+        disk.write_superblock();
+    });
+
+    assert_eq!(superblock::mark_mounted(&disk, "/mnt/ext4"), state);
+    let sb = disk.read_offset(ondisk::SUPERBLOCK_OFFSET);
+    assert_eq!(ondisk::le16(&sb, S_STATE) & EXT4_VALID_FS, 0);
+    assert_eq!(ondisk::le16(&sb, S_MNT_COUNT), mnt_count.wrapping_add(1));
+    assert_eq!(&sb[S_LAST_MOUNTED..][..10], b"/mnt/ext4\0");
+
+    superblock::mark_unmounted(&disk, state);
+    let sb = disk.read_offset(ondisk::SUPERBLOCK_OFFSET);
+    assert_eq!(ondisk::le16(&sb, S_STATE), state);
+}
+
+#[test]
+fn test_create_replies_match_getattr() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let req = Caller::default();
+    let root_links = fs.links_count(2);
+
+    let fifo = fs.add_node(&req, 2, OsStr::new("reply_fifo"), S_IFIFO | 0o666, 0o022, 0).unwrap();
+    let dir = fs.add_dir(&req, 2, OsStr::new("reply_dir"), 0o775, 0o002).unwrap();
+
+    for attr in [fifo, dir] {
+        let later = fs.get_attr(attr.ino).unwrap();
+        assert_eq!(attr.kind, later.kind);
+        assert_eq!(attr.perm, later.perm);
+        assert_eq!((attr.uid, attr.gid), (later.uid, later.gid));
+        assert_eq!(attr.nlink, later.nlink);
+        assert_eq!(attr.size, later.size);
+        assert_eq!(attr.blocks, later.blocks);
+        assert_eq!(attr.rdev, later.rdev);
+        assert_eq!(attr.atime, later.atime);
+        assert_eq!(attr.mtime, later.mtime);
+        assert_eq!(attr.ctime, later.ctime);
+        assert_eq!(attr.crtime, later.crtime);
+    }
+    assert_eq!((fifo.kind, fifo.perm, fifo.nlink), (FileType::NamedPipe, 0o644, 1));
+    assert_eq!((dir.kind, dir.perm, dir.nlink), (FileType::Directory, 0o775, 2));
+    assert_eq!(dir.size, BLOCK_SIZE as u64);
+
+    let root = fs.get_attr(2).unwrap();
+    assert_eq!(root.nlink, root_links as u32 + 1);
+    assert!(root.mtime >= dir.crtime && root.ctime >= dir.crtime);
+
+    // Other tools may leave any count; past the limit it becomes 1.
+    for links in [EXT4_LINK_MAX - 1, u16::MAX] {
+        fs.set_links_count(dir.ino as u32, links);
+        fs.add_dir_link(dir.ino as u32, true);
+        assert_eq!(fs.links_count(dir.ino as u32), 1);
+    }
+    fs.set_links_count(dir.ino as u32, 2);
+
+    fs.prepare_removal(2, OsStr::new("reply_fifo"));
+    fs.ext4.fuse_unlink(2, "reply_fifo").unwrap();
+    fs.ext4.fuse_rmdir(2, "reply_dir").unwrap();
+    fs.set_links_count(2, root_links);
+}
+
+#[test]
+fn test_setattr_owner_rules_and_truncate() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs
+        .ext4
+        .fuse_mknod_with_attr(2, "setattr_test", S_IFREG | 0o6755, 0, 0, 1000, 1000)
+        .unwrap()
+        .inode_num as u64;
+    let _cleanup = Cleanup(|| {
+        let _ = fs.ext4.fuse_unlink(2, "setattr_test");
+    });
+    let attr = fs.ext4.fuse_getattr(ino).unwrap();
+    let owner = Credentials { uid: 1000, gid: 1000, groups: vec![20] };
+    let other = Credentials { uid: 1001, gid: 1001, groups: vec![] };
+    let root = Credentials { uid: 0, gid: 0, groups: vec![] };
+
+    assert_eq!(fs.may_chown(&owner, &attr, Some(0), None), Err(EPERM));
+    assert_eq!(fs.may_chown(&owner, &attr, None, Some(20)), Ok(()));
+    assert_eq!(fs.may_chown(&owner, &attr, None, Some(0)), Err(EPERM));
+    assert_eq!(fs.may_chown(&other, &attr, None, Some(1001)), Err(EPERM));
+    assert_eq!(fs.may_chown(&root, &attr, Some(0), Some(0)), Ok(()));
+    assert_eq!(fs.may_chmod(&other, &attr, None, 0o777), Err(EPERM));
+    assert_eq!(fs.may_chmod(&owner, &attr, Some(0), 0o2755), Ok(0o755));
+    assert_eq!(fs.may_set_times(&other, &attr, Some(TimeOrNow::SpecificTime(UNIX_EPOCH)), None), Err(EPERM));
+
+    fs.kill_privs(ino);
+    assert_eq!(fs.raw_mode(ino) & 0o7777, 0o755);
+
+    let data = vec![0xAAu8; BLOCK_SIZE * 3];
+    fs.ext4.fuse_write(ino, 0, 0, &data, 0, 0, None).unwrap();
+    fs.truncate(ino, 100).unwrap();
+    assert_eq!(fs.extents().size(ino as u32), 100);
+    assert_eq!(fs.extents().load(ino as u32).unwrap().extents.iter().map(|e| e.len).sum::<u32>(), 1);
+    fs.truncate(ino, BLOCK_SIZE as u64 * 2).unwrap();
+    let read = fs.ext4.fuse_read(ino, 0, 0, BLOCK_SIZE as u32, 0, None).unwrap();
+    assert!(read[..100].iter().all(|&b| b == 0xAA) && read[100..].iter().all(|&b| b == 0));
+    assert_eq!(fs.truncate(ino, MAX_FILE_SIZE + 1), Err(EFBIG));
+    assert_eq!(fs.truncate(2, 0), Err(EISDIR));
+}
+
+#[test]
+fn test_setattr_kills_privs_for_group_writer() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs
+        .ext4
+        .fuse_mknod_with_attr(2, "killpriv_test", S_IFREG | 0o6775, 0, 0, 1000, 1000)
+        .unwrap()
+        .inode_num as u64;
+    fs.ext4.fuse_write(ino, 0, 0, &[0xAA; 100], 0, 0, None).unwrap();
+    let writer = Caller::new(1001, 1000, 0, Arc::new(vec![]));
+
+    // What the kernel sends for ftruncate(2) by a group member.
+    let attr = fs
+        .set_attr(&writer, ino, Some(S_IFREG | 0o775), None, None, Some(0), None, None, None, Some(0), None, None, None, None)
+        .unwrap();
+    assert_eq!((attr.size, fs.raw_mode(ino) & 0o7777), (0, 0o775));
+    let r = fs.set_attr(&writer, ino, Some(S_IFREG | 0o777), None, None, None, None, None, None, None, None, None, None, None);
+    assert_eq!(r.map(|attr| attr.ino), Err(EPERM));
+
+    fs.ext4.fuse_unlink(2, "killpriv_test").unwrap();
+}
+
+#[test]
+fn test_writeback_append_only() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs
+        .ext4
+        .fuse_mknod_with_attr(2, "append_test", S_IFREG | 0o644, 0, 0, 0, 0)
+        .unwrap()
+        .inode_num as u64;
+    fs.ext4.fuse_write(ino, 0, 0, &[0xAA; 100], 0, 0, None).unwrap();
+    fs.set_inode_flags(&Caller::default(), ino, iflags::EXT4_APPEND_FL).unwrap();
+
+    // A page flushed from the cache, holding the old bytes and the new.
+    let mut page = vec![0xAA; 100];
+    page.extend_from_slice(&[0xBB; 50]);
+    assert_eq!(fs.check_writable(ino, 0), Err(EPERM));
+    assert!(fs.only_appends(ino, 0, &page));
+    page[10] = 0;
+    assert!(!fs.only_appends(ino, 0, &page));
+    assert_eq!(fs.check_writable(ino, 100), Ok(()));
+
+    fs.set_inode_flags(&Caller::default(), ino, 0).unwrap();
+    fs.ext4.fuse_unlink(2, "append_test").unwrap();
+}
+
+#[test]
+fn test_setgid_directory_inheritance() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let req = Caller::default();
+    let member = Caller::new(1000, 1000, 0, Arc::new(vec![50]));
+    let outsider = Caller::new(1000, 1000, 0, Arc::new(vec![]));
+    let root_links = fs.links_count(2);
+
+    let top = fs.ext4.fuse_mkdir_with_attr(2, "setgid_test", 0o777, 0, 0, 50).unwrap().inode_num as u64;
+    let _cleanup = Cleanup(|| {
+        for name in ["file", "member", "outsider"] {
+            let _ = fs.ext4.fuse_unlink(top, name);
+        }
+        let _ = fs.ext4.fuse_rmdir(top, "sub");
+        let _ = fs.ext4.fuse_rmdir(2, "setgid_test");
+        fs.set_links_count(2, root_links);
+    });
+    fs.init_perm(top, 0o2777);
+
+    let file = fs.make_node(&req, top, OsStr::new("file"), S_IFREG | 0o2755, 0o022, 0).unwrap();
+    let sub = fs.make_dir(&req, top, OsStr::new("sub"), 0o755, 0o022).unwrap();
+    assert_eq!((file.gid, sub.gid), (50, 50));
+    assert_eq!(fs.raw_mode(sub.ino) & 0o7777, 0o2755);
+    // Root may keep setgid on a file in a group it is not in.
+    assert_eq!(fs.raw_mode(file.ino) & 0o7777, 0o2755);
+
+    // Others keep it only as members of the inherited group.
+    let kept = fs.make_node(&member, top, OsStr::new("member"), S_IFREG | 0o2755, 0o022, 0).unwrap();
+    let stripped = fs.make_node(&outsider, top, OsStr::new("outsider"), S_IFREG | 0o2755, 0o022, 0).unwrap();
+    assert_eq!((kept.gid, stripped.gid), (50, 50));
+    assert_eq!(fs.raw_mode(kept.ino) & 0o7777, 0o2755);
+    assert_eq!(fs.raw_mode(stripped.ino) & 0o7777, 0o755);
+}
+
+#[test]
+fn test_sticky_directory_removal() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let root_links = fs.links_count(2);
+
+    // World-writable, owned by 3000; the entries by 1000 and 2000.
+    let dir = fs.ext4.fuse_mkdir_with_attr(2, "sticky_test", 0o777, 0, 3000, 0).unwrap().inode_num as u64;
+    let _cleanup = Cleanup(|| {
+        for name in ["mine", "theirs"] {
+            let _ = fs.ext4.fuse_unlink(dir, name);
+        }
+        let _ = fs.ext4.fuse_rmdir(2, "sticky_test");
+        fs.set_links_count(2, root_links);
+    });
+    fs.init_perm(dir, 0o1777);
+    fs.ext4.fuse_mknod_with_attr(dir, "mine", S_IFREG | 0o644, 0, 0, 1000, 0).unwrap();
+    fs.ext4.fuse_mknod_with_attr(dir, "theirs", S_IFREG | 0o644, 0, 0, 2000, 0).unwrap();
+    let user = Caller::new(1000, 1000, 0, Arc::new(vec![]));
+    let owner = Caller::new(3000, 3000, 0, Arc::new(vec![]));
+    let (mine, theirs, new) = (OsStr::new("mine"), OsStr::new("theirs"), OsStr::new("new"));
+
+    assert_eq!(fs.may_delete(&user, dir, mine), Ok(()));
+    assert_eq!(fs.may_delete(&user, dir, theirs), Err(EPERM));
+    assert_eq!(fs.may_delete(&owner, dir, theirs), Ok(()));
+    assert_eq!(fs.may_delete(&Caller::default(), dir, theirs), Ok(()));
+
+    assert_eq!(fs.may_rename(&user, dir, mine, dir, new, 0), Ok(()));
+    assert_eq!(fs.may_rename(&user, dir, theirs, dir, new, 0), Err(EPERM));
+    // Replacing an entry counts as removing it.
+    assert_eq!(fs.may_rename(&user, dir, mine, dir, theirs, 0), Err(EPERM));
+    assert_eq!(fs.may_rename(&owner, dir, mine, dir, theirs, 0), Ok(()));
+
+    // Without the sticky bit write permission on the directory is enough.
+    fs.init_perm(dir, 0o777);
+    assert_eq!(fs.may_delete(&user, dir, theirs), Ok(()));
+}
+
+#[test]
+fn test_atime_policies() {
+    let _image = lock_image();
+    use atime::AtimePolicy;
+
+    let day = Duration::from_secs(24 * 60 * 60);
+    let now = SystemTime::now();
+    let (old, recent) = (now - day * 2, now - Duration::from_secs(60));
+    // Read since the last change, an hour ago.
+    let (atime, changed) = (recent, now - day / 24);
+
+    assert!(!AtimePolicy::NoAtime.wants_update(old, now, now, now));
+    assert!(AtimePolicy::StrictAtime.wants_update(atime, changed, changed, now));
+    assert!(!AtimePolicy::Relatime.wants_update(atime, changed, changed, now));
+    assert!(AtimePolicy::Relatime.wants_update(atime, recent, changed, now));
+    assert!(AtimePolicy::Relatime.wants_update(old, old - day, old - day, now));
+
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let config = Config { atime: AtimePolicy::StrictAtime, lazytime: true, ..Config::default() };
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, config);
+    let root_atime = ondisk::inode_time(&fs.layout.read_inode(&fs.disk, 2), InodeTime::Access);
+    let _cleanup = Cleanup(|| {
+        let mut raw = fs.layout.read_inode(&fs.disk, 2);
+        ondisk::set_inode_time(&mut raw, InodeTime::Access, root_atime);
+        fs.layout.write_inode(&fs.disk, 2, &mut raw);
+    });
+    let before = fs.layout.read_inode(&fs.disk, 2);
+    fs.note_access(2, &before);
+    let pending = fs.lazy_times.get(2, InodeTime::Access).unwrap();
+    let raw = fs.layout.read_inode(&fs.disk, 2);
+    assert_eq!(ondisk::inode_time(&raw, InodeTime::Access), ondisk::inode_time(&before, InodeTime::Access));
+    assert_eq!(fs.entry_attr(2).unwrap().atime, pending);
+
+    fs.sync_times(1, false);
+    let raw = fs.layout.read_inode(&fs.disk, 2);
+    assert_eq!(ondisk::inode_time(&raw, InodeTime::Access), pending);
+    assert_eq!(fs.lazy_times.get(2, InodeTime::Access), None);
+
+    // An update within the same second only shows in the extra field.
+    let mut raw = fs.layout.read_inode(&fs.disk, 2);
+    let second = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    ondisk::set_inode_time(&mut raw, InodeTime::Access, second + Duration::from_nanos(500));
+    fs.layout.write_inode(&fs.disk, 2, &mut raw);
+    let before = fs.layout.read_inode(&fs.disk, 2);
+    ondisk::set_inode_time(&mut raw, InodeTime::Access, second + Duration::from_nanos(900));
+    fs.layout.write_inode(&fs.disk, 2, &mut raw);
+    fs.restore_atime(2, &before);
+    let raw = fs.layout.read_inode(&fs.disk, 2);
+    assert_eq!(ondisk::inode_time(&raw, InodeTime::Access), second + Duration::from_nanos(500));
+}
+
+#[test]
+fn test_superblock_features() {
+    use features::Features;
+
+    let mut sb = vec![0u8; 1024];
+    // What mkfs.ext4 sets by default.
+    sb[0x60..0x64].copy_from_slice(&(0x2u32 | 0x40 | 0x80 | 0x200).to_le_bytes());
+    sb[0x64..0x68].copy_from_slice(&(0x1u32 | 0x2 | 0x8 | 0x20 | 0x40 | 0x400).to_le_bytes());
+    let features = Features::from_superblock(&sb);
+    assert_eq!(features.unsupported_incompat(), 0);
+    assert_eq!(features.unsupported_ro_compat(), 0);
+
+    // inline_data and quota.
+    let features = Features {
+        incompat: features.incompat | 0x8000,
+        ro_compat: features.ro_compat | 0x100,
+    };
+    assert_eq!(features.unsupported_incompat(), 0x8000);
+    assert_eq!(features.unsupported_ro_compat(), 0x100);
+}
+
+#[test]
+fn test_read_only_mount_leaves_image_alone() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let ino = fs.make_node(&Caller::default(), 2, OsStr::new("ro_atime"), S_IFREG | 0o644, 0, 0).unwrap().ino;
+    let old = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut raw = fs.layout.read_inode(&fs.disk, ino as u32);
+    ondisk::set_inode_time(&mut raw, InodeTime::Access, old);
+    fs.layout.write_inode(&fs.disk, ino as u32, &mut raw);
+
+    let ro_disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    ro_disk.set_read_only();
+    let config = Config {
+        read_only: true,
+        atime: atime::AtimePolicy::StrictAtime,
+        ..Config::default()
+    };
+    let ro = Ext4Fuse::new(Ext4::open(ro_disk.clone()), ro_disk.clone(), config);
+    let before = ro.layout.read_inode(&ro.disk, ino as u32);
+    ro.note_access(ino, &before);
+    ro.restore_atime(ino, &before);
+    assert_eq!(ro.layout.read_inode(&ro.disk, ino as u32), before);
+    assert_eq!(ro.entry_attr(ino).unwrap().atime, old);
+
+    // Not even a direct write gets through.
+    ro_disk.write_offset(ondisk::SUPERBLOCK_OFFSET, &[0xff; 16]);
+    assert_eq!(
+        ro_disk.read_offset(ondisk::SUPERBLOCK_OFFSET),
+        fs.disk.read_offset(ondisk::SUPERBLOCK_OFFSET)
+    );
+
+    fs.ext4.fuse_unlink(2, "ro_atime").unwrap();
+}
+
+#[test]
+fn test_flush_reports_deferred_write_errors() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    assert_eq!(fs.deferred_error(), Ok(()));
+
+    // Past the largest offset pwrite(2) takes.
+    fs.disk.write_offset(1 << 63, &[0; 8]);
+    assert_eq!(fs.deferred_error(), Err(EIO));
+    // Still there for fsync, which reports it once.
+    assert_eq!(fs.deferred_error(), Err(EIO));
+    assert_eq!(fs.sync(true), Err(EIO));
+    assert_eq!(fs.deferred_error(), Ok(()));
+    assert_eq!(fs.sync(true), Ok(()));
+}
+
+#[test]
+fn test_xattr_system_namespace() {
+    use ext4_rs::InodeFileType;
+
+    let (index, suffix) = xattr::parse_name(b"system.posix_acl_access").unwrap();
+    assert_eq!((index, suffix), (xattr::XATTR_INDEX_POSIX_ACL_ACCESS, &b""[..]));
+    assert_eq!(xattr::check_namespace(index, 1000, InodeFileType::S_IFREG, true), Ok(()));
+
+    let (index, suffix) = xattr::parse_name(b"system.sockprotoname").unwrap();
+    assert_eq!((index, suffix), (xattr::XATTR_INDEX_SYSTEM, &b"sockprotoname"[..]));
+    for uid in [0, 1000] {
+        for write in [false, true] {
+            assert_eq!(xattr::check_namespace(index, uid, InodeFileType::S_IFREG, write), Err(EOPNOTSUPP));
+        }
+        assert!(!xattr::is_listable(index, uid));
+    }
+}
+
+#[test]
+fn test_timeout_options_and_negative_entry() {
+    let parse = |opts: &str| {
+        let args: Vec<String> = ["ext4libtest", "-o", opts, "/mnt"].iter().map(|arg| arg.to_string()).collect();
+        Config::from_args(&args)
+    };
+    let config = parse("entry_timeout=2.5,attr_timeout=0,negative_timeout=30").unwrap();
+    assert_eq!(config.entry_ttl, Duration::from_millis(2500));
+    assert_eq!(config.attr_ttl, Duration::ZERO);
+    assert_eq!(config.negative_ttl, Duration::from_secs(30));
+    for bad in ["-1", "nan", "inf", "1s", ""] {
+        assert!(parse(&format!("entry_timeout={}", bad)).is_err(), "{:?} accepted", bad);
+    }
+
+    // Inode 0 is what makes the kernel cache a miss.
+    let entry = negative_entry();
+    assert_eq!((entry.ino, entry.nlink), (0, 0));
+
+    let notify = notify::Notify::default();
+    notify.entry_cached(2, OsStr::new("missing"), config.negative_ttl);
+    notify.entry_cached(7, OsStr::new("gone"), Duration::from_nanos(1));
+    notify.entry_cached(7, OsStr::new("uncached"), Duration::ZERO);
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(notify.take_entries(), vec![(2, OsString::from("missing"))]);
+    assert!(notify.take_entries().is_empty());
+}
+
+#[test]
+fn test_changed_externally() {
+    let _image = lock_image();
+    let disk = Disk::open(IMAGE_PATH).unwrap();
+    let other = Disk::open(IMAGE_PATH).unwrap();
+    disk.changed_externally();
+    let sb = disk.read_offset(ondisk::SUPERBLOCK_OFFSET);
+
+    // Our own writes do not count.
+    disk.write_offset(ondisk::SUPERBLOCK_OFFSET, &sb);
+    assert!(!disk.changed_externally());
+
+    // An outside write is seen even if ours comes after it.
+    std::thread::sleep(Duration::from_millis(20));
+    other.write_offset(ondisk::SUPERBLOCK_OFFSET, &sb);
+    std::thread::sleep(Duration::from_millis(20));
+    disk.write_offset(ondisk::SUPERBLOCK_OFFSET, &sb);
+    assert!(disk.changed_externally());
+    assert!(!disk.changed_externally());
+}
+
+#[test]
+fn test_lazytime_flushes_on_forget_and_age() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let config = Config {
+        lazytime: true,
+        atime: atime::AtimePolicy::StrictAtime,
+        ..Config::default()
+    };
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, config);
+    let req = Caller::default();
+    let a = fs.make_node(&req, 2, OsStr::new("lazy_a"), S_IFREG | 0o644, 0, 0).unwrap().ino;
+    let b = fs.make_node(&req, 2, OsStr::new("lazy_b"), S_IFREG | 0o644, 0, 0).unwrap().ino;
+    let _cleanup = Cleanup(|| {
+        let _ = fs.ext4.fuse_unlink(2, "lazy_a");
+        let _ = fs.ext4.fuse_unlink(2, "lazy_b");
+    });
+    let on_disk = |ino: u64, which| ondisk::inode_time(&fs.layout.read_inode(&fs.disk, ino as u32), which);
+
+    // Held back by a read and a write, then written when the kernel
+    // forgets the inode.
+    fs.inode_table.lookup(a);
+    let before = fs.layout.read_inode(&fs.disk, a as u32);
+    fs.note_access(a, &before);
+    std::thread::sleep(Duration::from_millis(10));
+    fs.touch_data(a);
+    let read = fs.lazy_times.get(a, InodeTime::Access).unwrap();
+    let written = fs.lazy_times.get(a, InodeTime::Modify).unwrap();
+    assert_eq!(fs.lazy_times.get(a, InodeTime::Change), Some(written));
+    assert_eq!(on_disk(a, InodeTime::Access), ondisk::inode_time(&before, InodeTime::Access));
+    assert_eq!(on_disk(a, InodeTime::Modify), ondisk::inode_time(&before, InodeTime::Modify));
+    let attr = fs.entry_attr(a).unwrap();
+    assert_eq!((attr.atime, attr.mtime, attr.ctime), (read, written, written));
+    fs.forget(&req, a, 1);
+    assert!(fs.lazy_times.take(a).is_empty());
+    assert_eq!(on_disk(a, InodeTime::Access), read);
+    assert_eq!(on_disk(a, InodeTime::Modify), written);
+    assert_eq!(on_disk(a, InodeTime::Change), written);
+
+    // Held back longer than allowed. A ctime stored meanwhile replaces the
+    // one held back.
+    let before = fs.layout.read_inode(&fs.disk, b as u32);
+    fs.note_access(b, &before);
+    fs.touch_data(b);
+    let read = fs.lazy_times.get(b, InodeTime::Access).unwrap();
+    let written = fs.lazy_times.get(b, InodeTime::Modify).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    let changed = fs.touch(b, &[InodeTime::Change]);
+    assert_eq!(fs.lazy_times.get(b, InodeTime::Change), None);
+    fs.flush_old_times(atime::LAZYTIME_MAX_AGE);
+    assert_eq!(fs.lazy_times.get(b, InodeTime::Access), Some(read));
+    fs.flush_old_times(Duration::ZERO);
+    assert!(fs.lazy_times.take(b).is_empty());
+    assert_eq!(on_disk(b, InodeTime::Access), read);
+    assert_eq!(on_disk(b, InodeTime::Modify), written);
+    assert_eq!(on_disk(b, InodeTime::Change), changed);
+}
+
+#[test]
+fn test_special_file_failures() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let _cleanup = Cleanup(|| {
+        let _ = fs.ext4.fuse_unlink(2, "special_dev");
+        let _ = fs.ext4.fuse_unlink(2, "special_half");
+    });
+    let rdev = (8 << 8) | 1;
+
+    // A removal that fails leaves the device number as it was.
+    let dev = fs.make_node(&Caller::default(), 2, OsStr::new("special_dev"), S_IFBLK | 0o600, 0, rdev).unwrap();
+    let prepared = fs.prepare_removal(2, OsStr::new("special_dev"));
+    assert!(prepared.is_some());
+    fs.undo_removal(prepared);
+    assert_eq!(fs.entry_attr(dev.ino).unwrap().rdev, rdev);
+    assert_eq!(fs.inode_flags(dev.ino) & EXT4_EXTENTS_FL, 0);
+    fs.prepare_removal(2, OsStr::new("special_dev"));
+    fs.ext4.fuse_unlink(2, "special_dev").unwrap();
+
+    // A node that could not be made special is not left behind.
+    let name = OsStr::new("special_half");
+    let ino = fs.ext4.fuse_mknod_with_attr(2, "special_half", S_IFCHR | 0o600, 0, rdev, 0, 0).unwrap().inode_num;
+    assert_eq!(fs.make_special(2, OsStr::new("special_other"), ino, S_IFCHR, rdev), Err(ENOENT));
+    fs.discard_new(2, name, ino);
+    assert_eq!(fs.lookup_name(2, name).map(|attr| attr.ino), Err(ENOENT));
+    assert_eq!(fs.links_count(ino), 0);
+}
+
+#[test]
+fn test_dir_snapshots_and_rewinddir() {
+    let _image = lock_image();
+    let disk = Arc::new(Disk::open(IMAGE_PATH).unwrap());
+    let fs = Ext4Fuse::new(Ext4::open(disk.clone()), disk, Config::default());
+    let req = Caller::default();
+    let root_links = fs.links_count(2);
+    let dir = fs.make_dir(&req, 2, OsStr::new("snapshot_dir"), 0o755, 0).unwrap().ino;
+    let _cleanup = Cleanup(|| {
+        for name in ["a", "b", "c"] {
+            let _ = fs.ext4.fuse_unlink(dir, name);
+        }
+        let _ = fs.ext4.fuse_rmdir(2, "snapshot_dir");
+        fs.set_links_count(2, root_links);
+    });
+    for name in ["a", "b", "c"] {
+        fs.make_node(&req, dir, OsStr::new(name), S_IFREG | 0o644, 0, 0).unwrap();
+    }
+    let names = |entries: &[DirEntry]| -> Vec<OsString> {
+        let mut names: Vec<OsString> = entries.iter().map(|entry| entry.name.clone()).collect();
+        names.sort();
+        names
+    };
+    let all: Vec<OsString> = [".", "..", "a", "b", "c"].iter().map(OsString::from).collect();
+
+    let fh = fs.dirs.lock().unwrap().open();
+    let unread = fs.dirs.lock().unwrap().open();
+    let first = fs.dir_snapshot(dir, fh, 0).unwrap();
+    assert_eq!(names(&first), all);
+
+    // Removed by another thread between two reads of the handle.
+    std::thread::scope(|s| {
+        s.spawn(|| fs.ext4.fuse_unlink(dir, "b").unwrap()).join().unwrap();
+    });
+    let later = fs.dir_snapshot(dir, fh, 2).unwrap();
+    assert!(Arc::ptr_eq(&first, &later));
+    assert_eq!(names(&later), all);
+
+    // rewinddir, or a handle first read now, lists the directory afresh.
+    let rewound = fs.dir_snapshot(dir, fh, 0).unwrap();
+    assert!(!Arc::ptr_eq(&first, &rewound));
+    assert!(!names(&rewound).contains(&OsString::from("b")));
+    assert_eq!(names(&fs.dir_snapshot(dir, unread, 0).unwrap()), names(&rewound));
+    assert!(Arc::ptr_eq(&rewound, &fs.dir_snapshot(dir, fh, 3).unwrap()));
+
+    fs.dirs.lock().unwrap().release(fh);
+    fs.dirs.lock().unwrap().release(unread);
+    assert!(fs.dirs.lock().unwrap().get(fh).is_none());
+}
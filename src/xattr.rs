@@ -0,0 +1,427 @@
+//! Extended attributes, stored in the inode body after `i_extra_isize` and in
+//! an external xattr block referenced by `i_file_acl`.
+
+use crate::ondisk::{self, le16, le32, put16, put32, Layout, EXT4_GOOD_OLD_INODE_SIZE};
+use crate::{Disk, E2BIG, EEXIST, EINVAL, EIO, ENODATA, ENOSPC, EOPNOTSUPP, EPERM, ERANGE};
+use ext4_rs::{BlockDevice, Ext4, InodeFileType, BLOCK_SIZE};
+use std::ops::Range;
+
+pub const XATTR_CREATE: i32 = 1;
+pub const XATTR_REPLACE: i32 = 2;
+
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+const XATTR_HEADER_SIZE: usize = 32;
+const XATTR_ENTRY_SIZE: usize = 16;
+const XATTR_NAME_MAX: usize = 255;
+const XATTR_SIZE_MAX: usize = 65536;
+
+// Xattr block header field offsets.
+const H_REFCOUNT: usize = 0x04;
+const H_BLOCKS: usize = 0x08;
+const H_HASH: usize = 0x0C;
+const H_CHECKSUM: usize = 0x10;
+
+pub const XATTR_INDEX_USER: u8 = 1;
+pub const XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+pub const XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+pub const XATTR_INDEX_TRUSTED: u8 = 4;
+pub const XATTR_INDEX_SECURITY: u8 = 6;
+pub const XATTR_INDEX_SYSTEM: u8 = 7;
+
+/// Name prefix of each on-disk index. The ACL indices carry the full name.
+const PREFIXES: [(u8, &[u8]); 6] = [
+    (XATTR_INDEX_POSIX_ACL_ACCESS, b"system.posix_acl_access"),
+    (XATTR_INDEX_POSIX_ACL_DEFAULT, b"system.posix_acl_default"),
+    (XATTR_INDEX_USER, b"user."),
+    (XATTR_INDEX_TRUSTED, b"trusted."),
+    (XATTR_INDEX_SECURITY, b"security."),
+    (XATTR_INDEX_SYSTEM, b"system."),
+];
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub index: u8,
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    /// The name as seen by user space, or `None` for indices we don't know.
+    pub fn full_name(&self) -> Option<Vec<u8>> {
+        let (_, prefix) = PREFIXES.iter().find(|(index, _)| *index == self.index)?;
+        Some([*prefix, &self.name[..]].concat())
+    }
+
+    fn entry_size(&self) -> usize {
+        pad4(XATTR_ENTRY_SIZE + self.name.len())
+    }
+
+    fn disk_size(&self) -> usize {
+        self.entry_size() + pad4(self.value.len())
+    }
+
+    /// `e_hash`, computed like `ext4_xattr_hash_entry` with signed name chars.
+    fn hash(&self) -> u32 {
+        let mut hash: u32 = 0;
+        for &c in &self.name {
+            hash = hash.rotate_left(5) ^ (c as i8 as u32);
+        }
+        for chunk in self.value.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = hash.rotate_left(16) ^ u32::from_le_bytes(word);
+        }
+        hash
+    }
+
+    /// Entries in an xattr block are kept sorted by index, name length and name.
+    fn sort_key(&self) -> (u8, usize, &[u8]) {
+        (self.index, self.name.len(), &self.name)
+    }
+}
+
+/// Splits a user-visible attribute name into its on-disk index and suffix.
+pub fn parse_name(name: &[u8]) -> Result<(u8, &[u8]), i32> {
+    if name.len() > XATTR_NAME_MAX {
+        return Err(ERANGE);
+    }
+    for (index, prefix) in PREFIXES {
+        let Some(suffix) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        let is_acl = matches!(
+            index,
+            XATTR_INDEX_POSIX_ACL_ACCESS | XATTR_INDEX_POSIX_ACL_DEFAULT
+        );
+        match (is_acl, suffix.is_empty()) {
+            (true, false) => continue,
+            (false, true) => return Err(EINVAL),
+            _ => return Ok((index, suffix)),
+        }
+    }
+    Err(EOPNOTSUPP)
+}
+
+/// Namespace rules applied before the inode is touched, as in the kernel's
+/// `xattr_permission`. Of `system.*`, ext4 only handles the POSIX ACLs.
+pub fn check_namespace(index: u8, uid: u32, kind: InodeFileType, write: bool) -> Result<(), i32> {
+    let denied = if write { EPERM } else { ENODATA };
+    match index {
+        XATTR_INDEX_SYSTEM => Err(EOPNOTSUPP),
+        XATTR_INDEX_TRUSTED if uid != 0 => Err(denied),
+        XATTR_INDEX_SECURITY if write && uid != 0 => Err(EPERM),
+        XATTR_INDEX_USER
+            if !matches!(kind, InodeFileType::S_IFREG | InodeFileType::S_IFDIR) =>
+        {
+            Err(denied)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether `listxattr` shows attributes of this index to `uid`.
+pub fn is_listable(index: u8, uid: u32) -> bool {
+    match index {
+        XATTR_INDEX_SYSTEM => false,
+        XATTR_INDEX_TRUSTED => uid == 0,
+        _ => true,
+    }
+}
+
+/// The in-inode xattr area, from `128 + i_extra_isize` to the end of the inode.
+fn ibody_range(layout: &Layout, raw: &[u8]) -> Option<Range<usize>> {
+    if layout.inode_size <= EXT4_GOOD_OLD_INODE_SIZE {
+        return None;
+    }
+    let start = EXT4_GOOD_OLD_INODE_SIZE + le16(raw, ondisk::I_EXTRA_ISIZE) as usize;
+    // Room for the magic and the end-of-entries marker.
+    (start + 8 <= layout.inode_size).then_some(start..layout.inode_size)
+}
+
+/// Parses the entry table at `first`; value offsets are relative to `base`.
+/// Entries whose value is kept in an EA inode are left out, and the result
+/// tells whether there were any.
+fn parse_entries(buf: &[u8], first: usize, base: usize, out: &mut Vec<Xattr>) -> Result<bool, i32> {
+    let mut in_ea_inode = false;
+    let mut off = first;
+    while off + XATTR_ENTRY_SIZE <= buf.len() && le32(buf, off) != 0 {
+        let name_len = buf[off] as usize;
+        let index = buf[off + 1];
+        let value_offs = le16(buf, off + 2) as usize;
+        let value_inum = le32(buf, off + 4);
+        let value_size = le32(buf, off + 8) as usize;
+
+        let name_start = off + XATTR_ENTRY_SIZE;
+        if name_start + name_len > buf.len() {
+            log::error!("corrupted xattr entry at offset {}", off);
+            return Err(EIO);
+        }
+        off += pad4(XATTR_ENTRY_SIZE + name_len);
+
+        // The value is the contents of that inode, not part of `buf`.
+        if value_inum != 0 {
+            log::warn!("skipping xattr stored in EA inode {}", value_inum);
+            in_ea_inode = true;
+            continue;
+        }
+        let value_start = base + value_offs;
+        if value_start + value_size > buf.len() {
+            log::error!("corrupted xattr value at offset {}", value_start);
+            return Err(EIO);
+        }
+        out.push(Xattr {
+            index,
+            name: buf[name_start..name_start + name_len].to_vec(),
+            value: buf[value_start..value_start + value_size].to_vec(),
+        });
+    }
+    Ok(in_ea_inode)
+}
+
+/// Lays out `attrs` in a zeroed `buf`: entries grow up from `first` and
+/// values grow down from the end, with value offsets relative to `base`.
+fn encode_entries(attrs: &[Xattr], buf: &mut [u8], first: usize, base: usize) {
+    let mut entry = first;
+    let mut value_end = buf.len();
+    for attr in attrs {
+        let mut value_offs = 0;
+        if !attr.value.is_empty() {
+            value_end -= pad4(attr.value.len());
+            buf[value_end..value_end + attr.value.len()].copy_from_slice(&attr.value);
+            value_offs = value_end - base;
+        }
+
+        let name_start = entry + XATTR_ENTRY_SIZE;
+        buf[entry] = attr.name.len() as u8;
+        buf[entry + 1] = attr.index;
+        put16(buf, entry + 2, value_offs as u16);
+        put32(buf, entry + 4, 0);
+        put32(buf, entry + 8, attr.value.len() as u32);
+        put32(buf, entry + 12, attr.hash());
+        buf[name_start..name_start + attr.name.len()].copy_from_slice(&attr.name);
+        entry += attr.entry_size();
+    }
+}
+
+/// `h_hash` of an xattr block, mixed from the entry hashes.
+fn block_hash(attrs: &[Xattr]) -> u32 {
+    attrs
+        .iter()
+        .fold(0, |hash: u32, attr| hash.rotate_left(16) ^ attr.hash())
+}
+
+/// Extended attribute access on top of an opened image.
+pub struct XattrStore<'a> {
+    pub ext4: &'a Ext4,
+    pub disk: &'a Disk,
+    pub layout: &'a Layout,
+}
+
+impl XattrStore<'_> {
+    /// Returns every attribute of `ino`, in-inode entries first.
+    pub fn list(&self, ino: u32) -> Result<Vec<Xattr>, i32> {
+        self.load(ino).map(|(attrs, _)| attrs)
+    }
+
+    /// The attributes of `ino`, and whether any were left out because
+    /// their values are kept in EA inodes.
+    fn load(&self, ino: u32) -> Result<(Vec<Xattr>, bool), i32> {
+        let raw = self.layout.read_inode(self.disk, ino);
+        let mut attrs = Vec::new();
+        let mut in_ea_inode = false;
+
+        if let Some(range) = ibody_range(self.layout, &raw) {
+            let region = &raw[range];
+            if le32(region, 0) == XATTR_MAGIC {
+                in_ea_inode |= parse_entries(region, 4, 4, &mut attrs)?;
+            }
+        }
+
+        let block = ondisk::inode_file_acl(&raw);
+        if block != 0 {
+            let buf = self.read_block(block)?;
+            in_ea_inode |= parse_entries(&buf, XATTR_HEADER_SIZE, 0, &mut attrs)?;
+        }
+
+        Ok((attrs, in_ea_inode))
+    }
+
+    /// The attributes of `ino` to be rewritten. Rewriting would drop the
+    /// entries kept in EA inodes and leak those inodes, so it is refused.
+    fn load_for_update(&self, ino: u32) -> Result<Vec<Xattr>, i32> {
+        match self.load(ino)? {
+            (_, true) => Err(EOPNOTSUPP),
+            (attrs, false) => Ok(attrs),
+        }
+    }
+
+    pub fn get(&self, ino: u32, index: u8, name: &[u8]) -> Result<Option<Vec<u8>>, i32> {
+        let attrs = self.list(ino)?;
+        Ok(attrs
+            .into_iter()
+            .find(|attr| attr.index == index && attr.name == name)
+            .map(|attr| attr.value))
+    }
+
+    /// Sets one attribute, or removes it when `value` is `None`, honoring
+    /// XATTR_CREATE and XATTR_REPLACE.
+    pub fn set(
+        &self,
+        ino: u32,
+        index: u8,
+        name: &[u8],
+        value: Option<&[u8]>,
+        flags: i32,
+    ) -> Result<(), i32> {
+        if value.is_some_and(|value| value.len() > XATTR_SIZE_MAX) {
+            return Err(E2BIG);
+        }
+
+        let mut attrs = self.load_for_update(ino)?;
+        let pos = attrs
+            .iter()
+            .position(|attr| attr.index == index && attr.name == name);
+        match (pos, value) {
+            (Some(_), Some(_)) if flags & XATTR_CREATE != 0 => return Err(EEXIST),
+            (None, Some(_)) if flags & XATTR_REPLACE != 0 => return Err(ENODATA),
+            (None, None) => return Err(ENODATA),
+            (Some(i), None) => {
+                attrs.remove(i);
+            }
+            (Some(i), Some(value)) => attrs[i].value = value.to_vec(),
+            (None, Some(value)) => attrs.push(Xattr {
+                index,
+                name: name.to_vec(),
+                value: value.to_vec(),
+            }),
+        }
+
+        self.store(ino, attrs)
+    }
+
+    /// Drops every attribute of `ino`, releasing its xattr block.
+    pub fn clear(&self, ino: u32) -> Result<(), i32> {
+        self.load_for_update(ino)?;
+        self.store(ino, Vec::new())
+    }
+
+    /// Rewrites all attributes of `ino`, filling the inode body first and
+    /// spilling the rest into the xattr block.
+    fn store(&self, ino: u32, mut attrs: Vec<Xattr>) -> Result<(), i32> {
+        let raw = self.layout.read_inode(self.disk, ino);
+        let ibody = ibody_range(self.layout, &raw);
+
+        // Inline data must stay in the inode, so it is placed first.
+        attrs.sort_by_key(|attr| !(attr.index == XATTR_INDEX_SYSTEM && attr.name == b"data"));
+
+        let mut ibody_free = ibody.as_ref().map_or(0, |range| range.len() - 8);
+        let mut in_inode = Vec::new();
+        let mut in_block = Vec::new();
+        for attr in attrs {
+            if attr.disk_size() <= ibody_free {
+                ibody_free -= attr.disk_size();
+                in_inode.push(attr);
+            } else {
+                in_block.push(attr);
+            }
+        }
+
+        in_block.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        let block_size: usize = in_block.iter().map(Xattr::disk_size).sum();
+        if block_size + 4 > BLOCK_SIZE - XATTR_HEADER_SIZE {
+            return Err(ENOSPC);
+        }
+
+        let old_block = ondisk::inode_file_acl(&raw);
+        let new_block = self.update_block(ino, old_block, &in_block)?;
+
+        // ext4_rs may have written the inode back while allocating or freeing.
+        let mut raw = self.layout.read_inode(self.disk, ino);
+        if let Some(range) = ibody {
+            let region = &mut raw[range];
+            region.fill(0);
+            if !in_inode.is_empty() {
+                put32(region, 0, XATTR_MAGIC);
+                encode_entries(&in_inode, region, 4, 4);
+            }
+        }
+        ondisk::set_inode_file_acl(&mut raw, new_block);
+        self.layout.write_inode(self.disk, ino, &mut raw);
+
+        Ok(())
+    }
+
+    /// Writes `attrs` to the xattr block of `ino`, allocating, unsharing or
+    /// freeing it as needed, and returns the block the inode should refer to.
+    fn update_block(&self, ino: u32, old: u64, attrs: &[Xattr]) -> Result<u64, i32> {
+        if old != 0 {
+            let mut buf = self.read_block(old)?;
+            let refcount = le32(&buf, H_REFCOUNT);
+            if refcount <= 1 && !attrs.is_empty() {
+                self.write_block(old, attrs);
+                return Ok(old);
+            }
+
+            if refcount <= 1 {
+                let mut inode_ref = self.ext4.get_inode_ref(ino);
+                self.ext4.balloc_free_blocks(&mut inode_ref, old, 1);
+            } else {
+                // Shared with other inodes: only drop our reference.
+                put32(&mut buf, H_REFCOUNT, refcount - 1);
+                self.seal_block(old, &mut buf);
+                self.disk.write_offset(old as usize * BLOCK_SIZE, &buf);
+
+                let mut raw = self.layout.read_inode(self.disk, ino);
+                let blocks = le32(&raw, ondisk::I_BLOCKS_LO).saturating_sub((BLOCK_SIZE / 512) as u32);
+                put32(&mut raw, ondisk::I_BLOCKS_LO, blocks);
+                self.layout.write_inode(self.disk, ino, &mut raw);
+            }
+        }
+
+        if attrs.is_empty() {
+            return Ok(0);
+        }
+
+        let mut inode_ref = self.ext4.get_inode_ref(ino);
+        let block = self
+            .ext4
+            .balloc_alloc_block(&mut inode_ref, None)
+            .map_err(|e| {
+                log::error!("failed to allocate xattr block for inode {}: {:?}", ino, e);
+                ENOSPC
+            })?;
+        self.write_block(block, attrs);
+        Ok(block)
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, i32> {
+        let buf = self.disk.read_offset(block as usize * BLOCK_SIZE);
+        if le32(&buf, 0) != XATTR_MAGIC || le32(&buf, H_BLOCKS) != 1 {
+            log::error!("bad xattr block header in block {}", block);
+            return Err(EIO);
+        }
+        Ok(buf)
+    }
+
+    fn write_block(&self, block: u64, attrs: &[Xattr]) {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        put32(&mut buf, 0, XATTR_MAGIC);
+        put32(&mut buf, H_REFCOUNT, 1);
+        put32(&mut buf, H_BLOCKS, 1);
+        put32(&mut buf, H_HASH, block_hash(attrs));
+        encode_entries(attrs, &mut buf, XATTR_HEADER_SIZE, 0);
+        self.seal_block(block, &mut buf);
+        self.disk.write_offset(block as usize * BLOCK_SIZE, &buf);
+    }
+
+    fn seal_block(&self, block: u64, buf: &mut [u8]) {
+        if self.layout.has_metadata_csum() {
+            let csum = self.layout.xattr_block_checksum(block, buf, H_CHECKSUM);
+            put32(buf, H_CHECKSUM, csum);
+        }
+    }
+}